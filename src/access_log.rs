@@ -0,0 +1,56 @@
+//! One line per request, written to stdout from the access-log hook.
+
+use serde_json::json;
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// `<remote> "<method> <path>" <status> <latency>ms <request bytes>B <request id>`
+    Text,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+impl LogFormat {
+    /// Parses a `LOG_FORMAT` value; `Some(None)` means access logging is off.
+    pub fn from_name(name: &str) -> Option<Option<Self>> {
+        match name {
+            "text" => Some(Some(LogFormat::Text)),
+            "json" => Some(Some(LogFormat::Json)),
+            "none" => Some(None),
+            _ => None,
+        }
+    }
+}
+
+pub fn write(format: LogFormat, info: &warp::log::Info, request_id: Option<&str>) {
+    let latency_ms = info.elapsed().as_secs_f64() * 1000.0;
+    // Chunked bodies have no length up front and are logged as 0 bytes.
+    let request_bytes: u64 = info
+        .request_headers()
+        .get(warp::http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok())
+        .unwrap_or(0);
+    let remote = info.remote_addr().map(|addr| addr.ip().to_string());
+    match format {
+        LogFormat::Text => println!(
+            "{} \"{} {}\" {} {latency_ms:.3}ms {request_bytes}B {}",
+            remote.as_deref().unwrap_or("-"),
+            info.method(),
+            info.path(),
+            info.status().as_u16(),
+            request_id.unwrap_or("-"),
+        ),
+        LogFormat::Json => println!(
+            "{}",
+            json!({
+                "remote_addr": remote,
+                "method": info.method().as_str(),
+                "path": info.path(),
+                "status": info.status().as_u16(),
+                "latency_ms": latency_ms,
+                "request_bytes": request_bytes,
+                "request_id": request_id,
+            })
+        ),
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Rejection};
+
+/// Rejection for requests without a valid API key.
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Passes requests carrying `key` as `Authorization: Bearer <key>` or `X-API-Key: <key>`
+/// and rejects the rest with [`Unauthorized`]. Without a key every request passes.
+pub fn require_api_key(key: Option<String>) -> BoxedFilter<()> {
+    let key: Option<Arc<str>> = key.map(Into::into);
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and_then(move |authorization: Option<String>, api_key: Option<String>| {
+            let key = key.clone();
+            async move {
+                let Some(key) = key else {
+                    return Ok::<_, Rejection>(());
+                };
+                let presented = authorization
+                    .as_deref()
+                    .and_then(bearer_token)
+                    .or(api_key.as_deref());
+                match presented {
+                    Some(presented) if constant_time_eq(presented.as_bytes(), key.as_bytes()) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// The credentials of a `Bearer` authorization header. The scheme is case-insensitive.
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim_start_matches(' '))
+}
+
+/// Compares without exiting early, so response timing does not reveal how much of a guess
+/// was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bearer_token;
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert_eq!(bearer_token("Bearer secret"), Some("secret"));
+        assert_eq!(bearer_token("bearer secret"), Some("secret"));
+        assert_eq!(bearer_token("BEARER  secret"), Some("secret"));
+        assert_eq!(bearer_token("Basic secret"), None);
+        assert_eq!(bearer_token("Bearersecret"), None);
+    }
+}
@@ -0,0 +1,75 @@
+//! Request bodies, decompressed when sent with `Content-Encoding: gzip` or `deflate` and
+//! size-limited both before and after decompression.
+
+use std::io::Read;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde::de::DeserializeOwned;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection};
+
+/// Rejection for a body that is larger than its route allows once decompressed.
+#[derive(Debug)]
+pub struct TooLarge;
+
+impl warp::reject::Reject for TooLarge {}
+
+/// Rejection for a `Content-Encoding` other than `gzip`, `deflate` or `identity`.
+#[derive(Debug)]
+pub struct UnsupportedEncoding(pub String);
+
+impl warp::reject::Reject for UnsupportedEncoding {}
+
+/// Rejection for a body that cannot be decompressed or does not have the expected shape.
+#[derive(Debug)]
+pub struct InvalidBody(pub String);
+
+impl warp::reject::Reject for InvalidBody {}
+
+/// The decompressed body, at most `limit` bytes before and after decompression.
+pub fn bytes(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(limit)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::bytes())
+        .and_then(move |encoding: Option<String>, body: Bytes| async move {
+            let gzip = match encoding.as_deref().map(str::trim) {
+                None | Some("") | Some("identity") => return Ok(body),
+                Some("gzip") | Some("x-gzip") => true,
+                Some("deflate") => false,
+                Some(other) => return Err(warp::reject::custom(UnsupportedEncoding(other.to_string()))),
+            };
+            // Inflating up to `limit` bytes is CPU-bound, so it runs off the async workers.
+            tokio::task::spawn_blocking(move || {
+                if gzip {
+                    decompress(GzDecoder::new(&body[..]), limit)
+                } else {
+                    decompress(ZlibDecoder::new(&body[..]), limit)
+                }
+            })
+            .await
+            .map_err(|e| warp::reject::custom(InvalidBody(format!("cannot decompress: {e}"))))?
+        })
+}
+
+/// The decompressed body parsed as JSON.
+pub fn json<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    bytes(limit).and_then(|body: Bytes| async move {
+        serde_json::from_slice(&body).map_err(|e| warp::reject::custom(InvalidBody(e.to_string())))
+    })
+}
+
+/// Reads one byte past `limit` so an oversized body is caught without inflating all of it.
+fn decompress(decoder: impl Read, limit: u64) -> Result<Bytes, Rejection> {
+    let mut out = Vec::new();
+    decoder
+        .take(limit + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| warp::reject::custom(InvalidBody(format!("cannot decompress: {e}"))))?;
+    if out.len() as u64 > limit {
+        return Err(warp::reject::custom(TooLarge));
+    }
+    Ok(out.into())
+}
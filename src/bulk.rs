@@ -0,0 +1,61 @@
+//! Parsing of Elasticsearch-style newline-delimited bulk bodies: each action line
+//! (`{"index": {}}`, `{"create": {}}` or `{"delete": {"_id": 1}}`) is followed by the
+//! document source for `index` and `create`.
+
+use serde_json::Value;
+
+pub enum BulkOp {
+    /// Add a document; the error is the reason its source line failed to parse.
+    Index(Result<Value, String>),
+    /// Remove the document with this id; `None` when the action carried no usable `_id`.
+    Delete(Option<usize>),
+    /// A recognised action this server does not implement.
+    Unsupported(String),
+}
+
+/// Splits `body` into operations. Malformed action lines fail the whole request, since the
+/// lines that follow can no longer be attributed; a malformed document only fails its item.
+pub fn parse(body: &str) -> Result<Vec<BulkOp>, String> {
+    let mut lines = body
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+    let mut ops = Vec::new();
+    while let Some((n, line)) = lines.next() {
+        let action: Value =
+            serde_json::from_str(line).map_err(|e| format!("line {n}: invalid action: {e}"))?;
+        let Some((name, meta)) = action
+            .as_object()
+            .filter(|obj| obj.len() == 1)
+            .and_then(|obj| obj.iter().next())
+        else {
+            return Err(format!("line {n}: expected an object with exactly one action"));
+        };
+        match name.as_str() {
+            "index" | "create" => {
+                let Some((_, source)) = lines.next() else {
+                    return Err(format!("line {n}: {name} action without a document"));
+                };
+                ops.push(BulkOp::Index(serde_json::from_str(source).map_err(|e| e.to_string())));
+            }
+            "delete" => ops.push(BulkOp::Delete(parse_id(meta.get("_id")))),
+            "update" => {
+                // Skip the partial document that belongs to the action.
+                lines.next();
+                ops.push(BulkOp::Unsupported(name.clone()));
+            }
+            other => return Err(format!("line {n}: unknown action {other:?}")),
+        }
+    }
+    Ok(ops)
+}
+
+/// Accepts ids as numbers or numeric strings, as Elasticsearch clients send them.
+fn parse_id(id: Option<&Value>) -> Option<usize> {
+    match id? {
+        Value::Number(n) => n.as_u64().map(|n| n as usize),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
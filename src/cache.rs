@@ -0,0 +1,82 @@
+//! Serialized search responses kept for a short while, so dashboards polling the same query
+//! do not run it again. An entry is only served while its index is at the generation it was
+//! computed from, so any write to the index invalidates it without further bookkeeping.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    generation: u64,
+    body: String,
+    /// Matches before the response was cut to its limit, sent as `X-Total-Count`.
+    total: usize,
+    expires: Instant,
+    /// Tick of the last lookup that returned this entry, for least-recently-used eviction.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<(String, String), Entry>,
+    tick: u64,
+}
+
+pub struct SearchCache {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl SearchCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        SearchCache {
+            ttl,
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The response and match count stored for `query` on `index`, if it has not expired and
+    /// the index is still at `generation`.
+    pub fn get(&self, index: &str, query: &str, generation: u64) -> Option<(String, usize)> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let tick = entries.tick;
+        let key = (index.to_string(), query.to_string());
+        let entry = entries.map.get_mut(&key)?;
+        if entry.generation != generation || entry.expires <= Instant::now() {
+            entries.map.remove(&key);
+            return None;
+        }
+        entry.used = tick;
+        Some((entry.body.clone(), entry.total))
+    }
+
+    /// Stores a response, evicting expired entries and then the least recently used one
+    /// when the cache is full.
+    pub fn put(&self, index: &str, query: &str, generation: u64, body: String, total: usize) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.tick += 1;
+        let now = Instant::now();
+        if entries.map.len() >= self.capacity {
+            entries.map.retain(|_, e| e.expires > now);
+        }
+        if entries.map.len() >= self.capacity
+            && let Some(oldest) = entries.map.iter().min_by_key(|(_, e)| e.used).map(|(k, _)| k.clone())
+        {
+            entries.map.remove(&oldest);
+        }
+        let entry = Entry {
+            generation,
+            body,
+            total,
+            expires: now + self.ttl,
+            used: entries.tick,
+        };
+        entries.map.insert((index.to_string(), query.to_string()), entry);
+    }
+}
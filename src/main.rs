@@ -1,161 +1,2620 @@
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::fs;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use warp::{Filter, Rejection, Reply};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+
+mod access_log;
+mod auth;
+mod body;
+mod bulk;
+mod cache;
+mod metrics;
+mod openapi;
+mod rate_limit;
+mod request_id;
+mod scroll;
+mod storage;
+mod strict_json;
+mod utils;
+
+use access_log::LogFormat;
+use bulk::BulkOp;
+use cache::SearchCache;
+use metrics::Metrics;
+use rate_limit::RateLimiter;
+use scroll::Scrolls;
+use storage::{StorageFormat, WalRecord};
+use utils::{
+    collect_highlights, geo_point, get_path, haversine_km, paths_containing, select_paths, tokenize, value_size,
+    Analyzer, Normalizer, QueryTerm, Typeahead, Wildcard,
+};
 
 #[derive(Clone, Serialize, Deserialize)]
 struct Document {
     id: usize,
+    /// Starts at 1 and goes up by one with every update, for `If-Match` preconditions.
+    #[serde(default)]
+    version: u64,
     #[serde(flatten)]
     data: Value,
 }
 
+/// The documents containing a token and how often it occurs in each.
+#[derive(Default, Clone)]
+struct Postings {
+    /// Ascending positions in `Index::docs`.
+    positions: Vec<usize>,
+    /// Occurrences of the token in the document at the same slot of `positions`.
+    counts: Vec<u32>,
+}
+
+impl Postings {
+    fn count(&self, pos: usize) -> u32 {
+        self.positions
+            .binary_search(&pos)
+            .map_or(0, |slot| self.counts[slot])
+    }
+}
+
+/// The indexed tokens one query term matches, each with its postings and the weight of
+/// the match.
+type Expansion<'a> = Vec<(&'a str, &'a Postings, f64)>;
+
 #[derive(Default, Clone, Serialize, Deserialize)]
 struct Index {
     docs: Vec<Document>,
+    #[serde(skip)]
+    postings: HashMap<String, Postings>,
+    /// Number of tokens in the document at each position.
+    #[serde(skip)]
+    lengths: Vec<u32>,
+    /// Sum of `lengths`.
+    #[serde(skip)]
+    total_length: u64,
+    /// Log records written since the last snapshot; non-zero means the index is dirty.
+    #[serde(skip)]
+    wal_records: usize,
+    /// Produces the indexed tokens and must also be used for query terms.
+    #[serde(skip)]
+    analyzer: Arc<Analyzer>,
+    /// Id of the next document inserted. Never decreases, so deleted ids are not reused.
+    #[serde(skip)]
+    next_id: usize,
+    /// Soft-deleted document ids with their deletion time in milliseconds since the Unix
+    /// epoch. These documents stay indexed but hidden until the retention period elapses
+    /// and a compaction purges them, or for good when no `TOMBSTONE_RETENTION` is set.
+    #[serde(skip)]
+    tombstones: HashMap<usize, u64>,
+    /// Changes with every write, and no two indexes share one, so a cached search response
+    /// is current exactly while the generation it was computed from is.
+    #[serde(skip)]
+    generation: u64,
 }
 
-#[derive(Serialize, Deserialize)]
-struct PersistedDocument {
-    id: usize,
-    data: Vec<u8>, // JSON-encoded
+/// Source of [`Index::generation`] values.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// BM25 term frequency saturation.
+const BM25_K1: f64 = 1.2;
+/// BM25 document length normalization.
+const BM25_B: f64 = 0.75;
+
+/// The BM25 inputs and contribution of one token in one document.
+struct TermStats {
+    /// Occurrences in the document, plus any weight added by field boosts.
+    tf: f64,
+    /// Documents containing the token.
+    df: f64,
+    idf: f64,
+    /// Below 1 for matches that only count partly, such as fuzzy typeahead matches.
+    weight: f64,
+    score: f64,
+}
+
+impl Index {
+    fn new(analyzer: Arc<Analyzer>) -> Self {
+        Index {
+            analyzer,
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed),
+            ..Index::default()
+        }
+    }
+
+    fn touch(&mut self) {
+        self.generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn next_id(&self) -> usize {
+        self.next_id.max(1)
+    }
+
+    fn insert(&mut self, doc: Document) {
+        self.touch();
+        let pos = self.docs.len();
+        self.next_id = self.next_id.max(doc.id + 1);
+        let tokens = self.analyzer.value_tokens(&doc.data, Normalizer::default());
+        self.lengths.push(tokens.len() as u32);
+        self.total_length += tokens.len() as u64;
+        for token in tokens {
+            let postings = self.postings.entry(token).or_default();
+            if postings.positions.last() == Some(&pos) {
+                *postings.counts.last_mut().unwrap() += 1;
+            } else {
+                postings.positions.push(pos);
+                postings.counts.push(1);
+            }
+        }
+        self.docs.push(doc);
+    }
+
+    /// Rough bytes of memory held by the documents and the inverted index, ignoring
+    /// allocator overhead and spare capacity.
+    fn approximate_size(&self) -> usize {
+        let docs: usize = self
+            .docs
+            .iter()
+            .map(|d| std::mem::size_of::<Document>() + value_size(&d.data))
+            .sum();
+        let postings: usize = self
+            .postings
+            .iter()
+            .map(|(token, p)| {
+                std::mem::size_of::<(String, Postings)>()
+                    + token.len()
+                    + p.positions.len() * (std::mem::size_of::<usize>() + std::mem::size_of::<u32>())
+            })
+            .sum();
+        docs + postings + self.lengths.len() * std::mem::size_of::<u32>()
+    }
+
+    /// Empties the index and returns its documents, to be inserted again.
+    fn take_docs(&mut self) -> Vec<Document> {
+        self.touch();
+        self.postings.clear();
+        self.lengths.clear();
+        self.total_length = 0;
+        std::mem::take(&mut self.docs)
+    }
+
+    /// Drops the documents with the given ids and rebuilds the posting lists.
+    fn remove(&mut self, ids: &HashSet<usize>) {
+        self.tombstones.retain(|id, _| !ids.contains(id));
+        for doc in self.take_docs() {
+            if !ids.contains(&doc.id) {
+                self.insert(doc);
+            }
+        }
+    }
+
+    /// Soft-deletes the documents with the given ids at `at`.
+    fn mark_deleted(&mut self, ids: impl IntoIterator<Item = usize>, at: u64) {
+        self.touch();
+        self.tombstones.extend(ids.into_iter().map(|id| (id, at)));
+    }
+
+    /// Clears the tombstone of `id`.
+    fn unmark_deleted(&mut self, id: usize) {
+        self.touch();
+        self.tombstones.remove(&id);
+    }
+
+    fn is_deleted(&self, pos: usize) -> bool {
+        !self.tombstones.is_empty() && self.tombstones.contains_key(&self.docs[pos].id)
+    }
+
+    /// Documents that are not soft-deleted, in insertion order.
+    fn live_docs(&self) -> Cow<'_, [Document]> {
+        if self.tombstones.is_empty() {
+            Cow::Borrowed(&self.docs)
+        } else {
+            Cow::Owned(
+                self.docs
+                    .iter()
+                    .filter(|d| !self.tombstones.contains_key(&d.id))
+                    .cloned()
+                    .collect(),
+            )
+        }
+    }
+
+    fn live_count(&self) -> usize {
+        self.docs.len() - self.tombstones.len()
+    }
+
+    /// Drops the documents soft-deleted before `cutoff`, in milliseconds since the Unix
+    /// epoch, for good.
+    fn purge_deleted_before(&mut self, cutoff: u64) {
+        let ids: HashSet<usize> = self
+            .tombstones
+            .iter()
+            .filter(|(_, at)| **at < cutoff)
+            .map(|(id, _)| *id)
+            .collect();
+        if !ids.is_empty() {
+            self.remove(&ids);
+        }
+    }
+
+    /// Replaces the data of the documents with the given ids, keeping their positions. A
+    /// few changes are applied to the posting lists in place; many at once rebuild them,
+    /// since shifting long posting lists once per document would cost more.
+    fn update(&mut self, mut changes: HashMap<usize, Value>) {
+        if changes.len() * INCREMENTAL_UPDATE_RATIO <= self.docs.len() {
+            for (id, data) in changes {
+                if let Ok(pos) = self.docs.binary_search_by_key(&id, |d| d.id) {
+                    self.replace(pos, data);
+                }
+            }
+            return;
+        }
+        for mut doc in self.take_docs() {
+            if let Some(data) = changes.remove(&doc.id) {
+                doc.data = data;
+                doc.version += 1;
+            }
+            self.insert(doc);
+        }
+    }
+
+    /// Replaces the data of the document at `pos`, touching only the posting lists of
+    /// tokens whose count in it changes.
+    fn replace(&mut self, pos: usize, data: Value) {
+        self.touch();
+        let old = self.token_counts(&self.docs[pos].data);
+        let new = self.token_counts(&data);
+        let length: u32 = new.values().sum();
+        self.total_length = self.total_length - self.lengths[pos] as u64 + length as u64;
+        self.lengths[pos] = length;
+        for token in old.keys().filter(|token| !new.contains_key(*token)) {
+            if let Some(postings) = self.postings.get_mut(token)
+                && let Ok(slot) = postings.positions.binary_search(&pos)
+            {
+                postings.positions.remove(slot);
+                postings.counts.remove(slot);
+                if postings.positions.is_empty() {
+                    self.postings.remove(token);
+                }
+            }
+        }
+        for (token, count) in new {
+            if old.get(&token) == Some(&count) {
+                continue;
+            }
+            let postings = self.postings.entry(token).or_default();
+            match postings.positions.binary_search(&pos) {
+                Ok(slot) => postings.counts[slot] = count,
+                Err(slot) => {
+                    postings.positions.insert(slot, pos);
+                    postings.counts.insert(slot, count);
+                }
+            }
+        }
+        let doc = &mut self.docs[pos];
+        doc.data = data;
+        doc.version += 1;
+    }
+
+    /// Occurrences of each indexed token of `value`.
+    fn token_counts(&self, value: &Value) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        for token in self.analyzer.value_tokens(value, Normalizer::default()) {
+            *counts.entry(token).or_default() += 1;
+        }
+        counts
+    }
+
+    /// The indexed tokens matching each of `terms`, with the weight of each match. Indexed
+    /// tokens are passed through `normalizer` before comparison, which scans the vocabulary
+    /// unless it is the default.
+    fn expand(&self, terms: &[QueryTerm], normalizer: Normalizer) -> Vec<Expansion<'_>> {
+        terms
+            .iter()
+            .map(|term| match term {
+                QueryTerm::Exact(token) if normalizer == Normalizer::default() => self
+                    .postings
+                    .get_key_value(token)
+                    .map(|(token, postings)| (token.as_str(), postings, 1.0))
+                    .into_iter()
+                    .collect(),
+                term => self
+                    .postings
+                    .iter()
+                    .filter_map(|(token, postings)| {
+                        let normalized = if normalizer == Normalizer::default() {
+                            Cow::Borrowed(token.as_str())
+                        } else {
+                            Cow::Owned(normalizer.normalize(token))
+                        };
+                        term.matches(&normalized)
+                            .then(|| (token.as_str(), postings, term.weight(&normalized)))
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Positions of the documents containing a token of each expanded term, in insertion
+    /// order. An empty query matches every document.
+    fn matching(&self, expanded: &[Expansion]) -> Vec<usize> {
+        let mut lists: Vec<Cow<[usize]>> = Vec::new();
+        for tokens in expanded {
+            let list = match tokens.as_slice() {
+                [] => return Vec::new(),
+                [(_, postings, _)] => Cow::Borrowed(postings.positions.as_slice()),
+                tokens => {
+                    let mut union: Vec<usize> =
+                        tokens.iter().flat_map(|(_, p, _)| p.positions.iter().copied()).collect();
+                    union.sort_unstable();
+                    union.dedup();
+                    Cow::Owned(union)
+                }
+            };
+            lists.push(list);
+        }
+        lists.sort_by_key(|list| list.len());
+        let Some((first, rest)) = lists.split_first() else {
+            return (0..self.docs.len()).collect();
+        };
+        let in_all = |pos: &usize| rest.iter().all(|list| list.binary_search(pos).is_ok());
+        if first.len() >= PARALLEL_THRESHOLD {
+            first.par_iter().copied().filter(in_all).collect()
+        } else {
+            first.iter().copied().filter(in_all).collect()
+        }
+    }
+
+    /// BM25 relevance of the document at `pos`: the sum over query terms of the best
+    /// scoring token each expands to, scaled by the weight of the match. `extra` adds
+    /// weighted occurrences per token on top of the indexed counts, which is how field
+    /// boosts raise a term's frequency.
+    fn score(&self, pos: usize, expanded: &[Expansion], extra: &HashMap<String, f64>) -> f64 {
+        expanded
+            .iter()
+            .map(|tokens| {
+                tokens
+                    .iter()
+                    .map(|(token, postings, weight)| {
+                        self.term_stats(pos, token, postings, *weight, extra).score
+                    })
+                    .fold(0.0, f64::max)
+            })
+            // Not `sum()`, which yields -0.0 for an empty query.
+            .fold(0.0, |total, score| total + score)
+    }
+
+    /// Average document length in tokens.
+    fn average_length(&self) -> f64 {
+        self.total_length as f64 / (self.docs.len() as f64).max(1.0)
+    }
+
+    fn term_stats(
+        &self,
+        pos: usize,
+        token: &str,
+        postings: &Postings,
+        weight: f64,
+        extra: &HashMap<String, f64>,
+    ) -> TermStats {
+        let docs = self.docs.len() as f64;
+        let length = self.lengths[pos] as f64 / self.average_length().max(1.0);
+        let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * length);
+        let tf = postings.count(pos) as f64 + extra.get(token).copied().unwrap_or(0.0);
+        let df = postings.positions.len() as f64;
+        let idf = (1.0 + (docs - df + 0.5) / (df + 0.5)).ln();
+        TermStats {
+            tf,
+            df,
+            idf,
+            weight,
+            score: weight * idf * tf * (BM25_K1 + 1.0) / (tf + norm),
+        }
+    }
+
+    /// How [`Index::score`] arrives at the score of the document at `pos`: for each query
+    /// term, labelled by `labels`, the best scoring token with its BM25 inputs and the
+    /// fields it occurs in.
+    fn explain(
+        &self,
+        pos: usize,
+        expanded: &[Expansion],
+        extra: &HashMap<String, f64>,
+        labels: &[String],
+    ) -> Value {
+        let terms: Vec<Value> = expanded
+            .iter()
+            .zip(labels)
+            .map(|(tokens, label)| {
+                let best = tokens
+                    .iter()
+                    .map(|(token, postings, weight)| {
+                        (*token, self.term_stats(pos, token, postings, *weight, extra))
+                    })
+                    .filter(|(_, stats)| stats.score > 0.0)
+                    .max_by(|a, b| a.1.score.total_cmp(&b.1.score));
+                let Some((token, stats)) = best else {
+                    return json!({ "term": label, "score": 0.0 });
+                };
+                let mut fields = Vec::new();
+                paths_containing(&self.docs[pos].data, "", token, &self.analyzer, &mut fields);
+                let mut term = json!({
+                    "term": label,
+                    "token": token,
+                    "score": stats.score,
+                    "tf": stats.tf,
+                    "df": stats.df as u64,
+                    "idf": stats.idf,
+                    "fields": fields,
+                });
+                if stats.weight != 1.0 {
+                    term["weight"] = json!(stats.weight);
+                }
+                term
+            })
+            .collect();
+        json!({
+            "score": self.score(pos, expanded, extra),
+            "documents": self.docs.len(),
+            "length": self.lengths[pos],
+            "average_length": self.average_length(),
+            "k1": BM25_K1,
+            "b": BM25_B,
+            "terms": terms,
+        })
+    }
 }
 
+/// The live indexes. Writes log and sync before changing them and searches read them
+/// directly, so a write is searchable and durable once its response is sent.
 type Indexes = Arc<RwLock<HashMap<String, Index>>>;
 
+/// Settings read from the environment once at startup.
+struct Config {
+    /// Directory holding the index files. `DATA_DIR`, default `data`.
+    data_dir: PathBuf,
+    /// Log records after which an index is compacted into a new snapshot.
+    /// `WAL_COMPACT_EVERY`, default 1000.
+    wal_compact_every: usize,
+    /// How often the background flusher looks for indexes to compact.
+    /// `FLUSH_INTERVAL_MS`, default 1000.
+    flush_interval: Duration,
+    /// Encoding of snapshot files. `STORAGE_FORMAT`, `bincode` (default) or `json`.
+    storage_format: StorageFormat,
+    /// Compress snapshot files with zstd. `COMPRESS=zstd`; off by default.
+    compress: bool,
+    /// Key required on every route but `/health`. `API_KEY`; unset disables authentication.
+    api_key: Option<String>,
+    /// Origins allowed to make cross-origin requests. `ALLOWED_ORIGINS`, comma-separated or
+    /// `*` for any; unset disables CORS.
+    allowed_origins: Option<Vec<String>>,
+    /// Requests per second allowed per client IP. `RATE_LIMIT_RPS`; unset disables limiting.
+    rate_limit_rps: Option<f64>,
+    /// Requests a client may make in a burst. `RATE_LIMIT_BURST`, default the per-second rate.
+    rate_limit_burst: Option<f64>,
+    /// Hits returned when a search gives no `limit`. `DEFAULT_LIMIT`, default 10.
+    default_limit: usize,
+    /// Most hits a search may return, whatever `limit` asks for. `MAX_LIMIT`, default 1000.
+    max_limit: usize,
+    /// Largest request body accepted by the single-document and query routes.
+    /// `MAX_BODY_BYTES`, default 1 MiB.
+    max_body_bytes: u64,
+    /// Largest request body accepted by bulk, CSV import and restore. `MAX_BULK_BYTES`,
+    /// default 100 MiB.
+    max_bulk_bytes: u64,
+    /// How long soft-deleted documents are kept before a compaction purges them.
+    /// `TOMBSTONE_RETENTION`, in seconds; unset keeps them until they are undeleted.
+    tombstone_retention: Option<Duration>,
+    /// How long identical searches are answered from the response cache. `CACHE_TTL`, in
+    /// seconds; unset disables the cache.
+    cache_ttl: Option<Duration>,
+    /// Most responses the cache holds. `CACHE_SIZE`, default 1000.
+    cache_size: usize,
+    /// Most scroll cursors open at once. `MAX_OPEN_SCROLLS`, default 500.
+    max_open_scrolls: usize,
+    /// Format of the access log on stdout. `LOG_FORMAT`, `text` (default), `json` or `none`.
+    log_format: Option<LogFormat>,
+    /// Stopword and stemming filters for every index. `STOPWORDS`, comma-separated words or
+    /// `english`, and `STEMMER=english`; both off by default.
+    analyzer: Arc<Analyzer>,
+}
+
+impl Config {
+    /// The number of hits to return for a requested `limit`.
+    fn limit(&self, requested: Option<usize>) -> usize {
+        requested.unwrap_or(self.default_limit).min(self.max_limit)
+    }
+
+    fn from_env() -> Self {
+        let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".to_string());
+        let wal_compact_every = std::env::var("WAL_COMPACT_EVERY")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1000);
+        let flush_interval_ms = std::env::var("FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1000);
+        let storage_format = match std::env::var("STORAGE_FORMAT") {
+            Ok(name) => StorageFormat::from_name(&name).unwrap_or_else(|| {
+                eprintln!("unknown STORAGE_FORMAT {name:?}, using bincode");
+                StorageFormat::Bincode
+            }),
+            Err(_) => StorageFormat::Bincode,
+        };
+        let compress = match std::env::var("COMPRESS").as_deref() {
+            Ok("zstd") => true,
+            Ok("") | Ok("none") | Err(_) => false,
+            Ok(other) => {
+                eprintln!("unknown COMPRESS {other:?}, writing uncompressed snapshots");
+                false
+            }
+        };
+        let log_format = match std::env::var("LOG_FORMAT") {
+            Ok(name) => LogFormat::from_name(&name).unwrap_or_else(|| {
+                eprintln!("unknown LOG_FORMAT {name:?}, using text");
+                Some(LogFormat::Text)
+            }),
+            Err(_) => Some(LogFormat::Text),
+        };
+        let stopwords = std::env::var("STOPWORDS").unwrap_or_default();
+        let stem = match std::env::var("STEMMER").as_deref() {
+            Ok("english") => true,
+            Ok("") | Ok("none") | Err(_) => false,
+            Ok(other) => {
+                eprintln!("unknown STEMMER {other:?}, not stemming");
+                false
+            }
+        };
+        Config {
+            data_dir: PathBuf::from(data_dir),
+            wal_compact_every,
+            flush_interval: Duration::from_millis(flush_interval_ms),
+            storage_format,
+            compress,
+            api_key: std::env::var("API_KEY").ok().filter(|key| !key.is_empty()),
+            allowed_origins: std::env::var("ALLOWED_ORIGINS").ok().map(|origins| {
+                origins
+                    .split(',')
+                    .map(|o| o.trim().to_string())
+                    .filter(|o| !o.is_empty())
+                    .collect()
+            }),
+            rate_limit_rps: std::env::var("RATE_LIMIT_RPS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|rps: &f64| *rps > 0.0),
+            rate_limit_burst: std::env::var("RATE_LIMIT_BURST")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            default_limit: std::env::var("DEFAULT_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(10),
+            max_limit: std::env::var("MAX_LIMIT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1000),
+            max_body_bytes: std::env::var("MAX_BODY_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1024 * 1024),
+            max_bulk_bytes: std::env::var("MAX_BULK_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(100 * 1024 * 1024),
+            tombstone_retention: std::env::var("TOMBSTONE_RETENTION")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|secs: &f64| *secs >= 0.0 && secs.is_finite())
+                .map(Duration::from_secs_f64),
+            cache_ttl: std::env::var("CACHE_TTL")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|ttl: &f64| *ttl > 0.0 && ttl.is_finite())
+                .map(Duration::from_secs_f64),
+            cache_size: std::env::var("CACHE_SIZE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1000),
+            max_open_scrolls: std::env::var("MAX_OPEN_SCROLLS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(500),
+            log_format,
+            analyzer: Arc::new(Analyzer::new(
+                stopwords.split(',').map(str::trim).filter(|w| !w.is_empty()),
+                stem,
+            )),
+        }
+    }
+}
+
+/// An update changing at most one in this many documents edits the posting lists in place
+/// instead of rebuilding them.
+const INCREMENTAL_UPDATE_RATIO: usize = 64;
+
+/// Below this many candidates a search stays on the calling thread; fanning out costs more.
+const PARALLEL_THRESHOLD: usize = 4096;
+
 #[tokio::main]
 async fn main() {
     let port: u16 = std::env::var("PORT")
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(3000);
+    // A bare IP takes its port from PORT; only loopback is reachable unless configured.
+    let addr: SocketAddr = match std::env::var("BIND_ADDR") {
+        Ok(addr) => addr
+            .parse()
+            .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+            .unwrap_or_else(|_| {
+                eprintln!("invalid BIND_ADDR {addr:?}, using 127.0.0.1:{port}");
+                SocketAddr::from(([127, 0, 0, 1], port))
+            }),
+        Err(_) => SocketAddr::from(([127, 0, 0, 1], port)),
+    };
 
-    let indexes = load_indexes().await;
+    let tls = load_tls();
+    let config = Arc::new(Config::from_env());
+    let ready = Arc::new(AtomicBool::new(false));
+    let indexes = storage::load_indexes(&config).await;
+    ready.store(true, Ordering::Release);
+    tokio::spawn(storage::flush_loop(indexes.clone(), config.clone()));
+    let shutdown_indexes = indexes.clone();
+    let metrics = Arc::new(Metrics::default());
+    let authorized = auth::require_api_key(config.api_key.clone());
+    let limiter = config
+        .rate_limit_rps
+        .map(|rps| Arc::new(RateLimiter::new(rps, config.rate_limit_burst.unwrap_or(rps))));
+    let rate_limited = rate_limit::rate_limit(limiter);
     let indexes_filter = warp::any().map(move || indexes.clone());
+    let config_filter = {
+        let config = config.clone();
+        warp::any().map(move || config.clone())
+    };
+    let metrics_filter = {
+        let metrics = metrics.clone();
+        warp::any().map(move || metrics.clone())
+    };
+    let scrolls = Arc::new(Scrolls::new(config.max_open_scrolls));
+    let scrolls_filter = warp::any().map(move || scrolls.clone());
+    let cache = config
+        .cache_ttl
+        .map(|ttl| Arc::new(SearchCache::new(ttl, config.cache_size)));
+    let cache_filter = warp::any().map(move || cache.clone());
+    let max_body = config.max_body_bytes;
+    let max_bulk = config.max_bulk_bytes;
 
     let hello = warp::path::end().map(|| "Hello world");
 
+    let health = warp::path!("health")
+        .and(warp::get())
+        .map(|| warp::reply::json(&json!({ "status": "ok" })));
+
+    let readiness = warp::path!("ready")
+        .and(warp::get())
+        .map(move || {
+            let (status, code) = if ready.load(Ordering::Acquire) {
+                ("ready", warp::http::StatusCode::OK)
+            } else {
+                ("loading", warp::http::StatusCode::SERVICE_UNAVAILABLE)
+            };
+            warp::reply::with_status(warp::reply::json(&json!({ "status": status })), code)
+        });
+
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(metrics_filter)
+        .and(indexes_filter.clone())
+        .and_then(render_metrics);
+
+    let spec = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&openapi::spec()));
+
     let add_document = warp::path!("indexes" / String / "documents")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(body::json(max_body))
         .and(indexes_filter.clone())
+        .and(config_filter.clone())
         .and_then(add_document);
 
+    let patch_document = warp::path!("indexes" / String / "documents" / usize)
+        .and(warp::patch())
+        .and(warp::query::<VersionParam>())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(body::json(max_body))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(patch_document);
+
+    let delete_document = warp::path!("indexes" / String / "documents" / usize)
+        .and(warp::delete())
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(delete_document);
+
+    let undelete_document = warp::path!("indexes" / String / "documents" / usize / "_undelete")
+        .and(warp::post())
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(undelete_document);
+
+    let bulk = warp::path!("indexes" / String / "_bulk")
+        .and(warp::post())
+        .and(body::bytes(max_bulk))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(bulk_documents);
+
+    let import = warp::path!("indexes" / String / "_import_csv")
+        .and(warp::post())
+        .and(body::bytes(max_bulk))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(import_csv);
+
+    let export = warp::path!("indexes" / String / "_export")
+        .and(warp::get())
+        .and(warp::query::<ExportQuery>())
+        .and(indexes_filter.clone())
+        .and_then(export_documents);
+
+    let delete_query = warp::path!("indexes" / String / "_delete_by_query")
+        .and(warp::post())
+        .and(strict_json::body(max_body, unknown_search_field))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(delete_by_query);
+
+    let update_query = warp::path!("indexes" / String / "_update_by_query")
+        .and(warp::post())
+        .and(strict_json::body(max_body, unknown_update_field))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(update_by_query);
+
     let search = warp::path!("indexes" / String / "search")
         .and(warp::get())
         .and(warp::query::<SearchQuery>())
+        .and(warp::query::<ScrollParams>())
+        .and(warp::header::optional::<String>("accept"))
         .and(indexes_filter.clone())
+        .and(scrolls_filter.clone())
+        .and(cache_filter)
+        .and(config_filter.clone())
         .and_then(search_documents);
 
-    let routes = hello
+    let search_geo = warp::path!("indexes" / String / "search_geo")
+        .and(warp::get())
+        .and(warp::query::<GeoQuery>())
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(search_geo);
+
+    let scroll = warp::path!("_scroll")
+        .and(warp::get())
+        .and(warp::query::<ScrollId>())
+        .and(indexes_filter.clone())
+        .and(scrolls_filter)
+        .and_then(scroll_next);
+
+    let cross = warp::path!("_search")
+        .and(warp::get())
+        .and(warp::query::<CrossSearchQuery>())
+        .and(warp::query::<SearchQuery>())
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(cross_search);
+
+    let msearch = warp::path!("_msearch")
+        .and(warp::post())
+        .and(strict_json::body(max_body, unknown_msearch_field))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(multi_search);
+
+    let reindex = warp::path!("_reindex")
+        .and(warp::post())
+        .and(strict_json::body(max_body, unknown_reindex_field))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(reindex);
+
+    let stats = warp::path!("indexes" / String / "_stats")
+        .and(warp::get())
+        .and(indexes_filter.clone())
+        .and_then(index_stats);
+
+    let snapshot = warp::path!("indexes" / String / "_snapshot")
+        .and(warp::get())
+        .and(indexes_filter.clone())
+        .and_then(snapshot_index);
+
+    let restore = warp::path!("indexes" / String / "_restore")
+        .and(warp::post())
+        .and(body::bytes(max_bulk))
+        .and(indexes_filter.clone())
+        .and(config_filter.clone())
+        .and_then(restore_index);
+
+    let protected = hello
+        .or(readiness)
+        .or(metrics_route)
+        .or(spec)
         .or(add_document)
+        .or(patch_document)
+        .or(delete_document)
+        .or(undelete_document)
+        .or(bulk)
+        .or(import)
+        .or(export)
+        .or(delete_query)
+        .or(update_query)
+        .or(reindex)
         .or(search)
+        .or(search_geo)
+        .or(scroll)
+        .or(cross)
+        .or(msearch)
+        .or(stats)
+        .or(snapshot)
+        .or(restore);
+
+    let app = health
+        .or(rate_limited.and(authorized).and(protected))
+        .recover(handle_rejection);
+    // CORS answers preflight requests itself, so it sits outside the API-key check.
+    let app = match &config.allowed_origins {
+        Some(origins) => app.with(cors(origins)).map(Reply::into_response).boxed(),
+        None => app.map(Reply::into_response).boxed(),
+    };
+    let app = app.recover(handle_unmatched).unify().boxed();
+    let app = request_id::with_request_id(app);
+
+    let log_format = config.log_format;
+    let routes = app
+        .with(warp::log::custom(move |info| {
+            metrics.observe(&info);
+            let id = request_id::take();
+            if let Some(format) = log_format {
+                access_log::write(format, &info, id.as_deref());
+            }
+        }))
         .with(warp::compression::gzip());
 
-    println!("Server running on port {}", port);
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    let server = warp::serve(routes);
+    // TLS and plain servers are different future types.
+    type Serving = Pin<Box<dyn Future<Output = ()>>>;
+    let bound = match tls {
+        Some((cert, key)) => server
+            .tls()
+            .cert(cert)
+            .key(key)
+            .try_bind_with_graceful_shutdown(addr, shutdown_signal())
+            .map(|(addr, server)| (format!("https://{addr}"), Box::pin(server) as Serving)),
+        None => server
+            .try_bind_with_graceful_shutdown(addr, shutdown_signal())
+            .map(|(addr, server)| (format!("http://{addr}"), Box::pin(server) as Serving)),
+    };
+    let (url, server) = bound.unwrap_or_else(|e| {
+        eprintln!("cannot listen on {addr}: {e}");
+        std::process::exit(1);
+    });
+    println!("Server running on {url}");
+    server.await;
+    // Requests have drained, so nothing can change an index after this final compaction.
+    storage::flush_indexes(&shutdown_indexes, &config, 1).await;
+    println!("Server stopped");
 }
 
-#[derive(Deserialize)]
+/// Reads the PEM certificate chain and private key named by `TLS_CERT` and `TLS_KEY`, or
+/// `None` to serve plain HTTP when neither is set. Exits if only one is set or a file cannot
+/// be read, rather than falling back to plain HTTP.
+fn load_tls() -> Option<(Vec<u8>, Vec<u8>)> {
+    let read = |var: &str, path: &str, label: &str| {
+        let pem = std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("cannot read {var} {path:?}: {e}");
+            std::process::exit(1);
+        });
+        let text = String::from_utf8_lossy(&pem);
+        if !text.lines().any(|line| line.starts_with("-----BEGIN ") && line.contains(label)) {
+            eprintln!("{var} {path:?} is not a PEM file with a {label} block");
+            std::process::exit(1);
+        }
+        pem
+    };
+    match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => Some((
+            read("TLS_CERT", &cert, "CERTIFICATE"),
+            read("TLS_KEY", &key, "PRIVATE KEY"),
+        )),
+        (Err(_), Err(_)) => None,
+        _ => {
+            eprintln!("TLS_CERT and TLS_KEY must be set together");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Resolves on Ctrl-C (SIGINT) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// A new field also belongs in [`SEARCH_FIELDS`] and the parameters of [`openapi::spec`].
+/// Serializing gives the key of the response cache.
+#[derive(Serialize, Deserialize)]
 struct SearchQuery {
     q: String,
+    /// Adds a `highlights` object marking matched terms in string fields.
+    #[serde(default)]
+    highlight: bool,
+    /// Tag wrapped around highlighted terms, `em` by default.
+    highlight_tag: Option<String>,
+    /// Comma-separated (dotted) field paths to search; the whole document when omitted.
+    fields: Option<String>,
+    /// Treats each whitespace-separated word of `q` as a `*`/`?` pattern.
+    #[serde(default)]
+    wildcard: bool,
+    /// Matches letter case exactly instead of ignoring it.
+    #[serde(default)]
+    case_sensitive: bool,
+    /// Ignores diacritics, so `cafe` matches `café` and vice versa.
+    #[serde(default)]
+    fold: bool,
+    /// Requires the terms to appear next to each other, in order, within one field.
+    #[serde(default)]
+    phrase: bool,
+    /// Matches each word of `q` against the start of tokens, allowing small typos, for
+    /// search-as-you-type. Ignored with `wildcard`.
+    #[serde(default)]
+    typeahead: bool,
+    /// Comma-separated `field:multiplier` pairs weighting matches in those fields.
+    boosts: Option<String>,
+    /// Comma-separated (dotted) field paths to return from each hit's document; all fields
+    /// when omitted and no document at all when `false`.
+    #[serde(rename = "_source")]
+    source: Option<String>,
+    /// Most hits to return; see [`Config::limit`]. Ignored by delete and update by query.
+    limit: Option<usize>,
+    /// Adds an `explanation` object breaking down each hit's score.
+    #[serde(default)]
+    explain: bool,
+    /// Also returns soft-deleted documents, marked with `deleted_at`. Ignored by delete and
+    /// update by query and by reindex.
+    #[serde(default)]
+    include_deleted: bool,
 }
 
-async fn add_document(index: String, doc: Value, indexes: Indexes) -> Result<impl Reply, Rejection> {
-    let mut map = indexes.write().await;
-    let entry = map.entry(index.clone()).or_default();
-    let id = entry.docs.len() + 1;
-    entry.docs.push(Document { id, data: doc });
+/// Keys accepted in a JSON search body; a test checks them against [`SearchQuery`].
+const SEARCH_FIELDS: &[&str] = &[
+    "q",
+    "highlight",
+    "highlight_tag",
+    "fields",
+    "wildcard",
+    "case_sensitive",
+    "fold",
+    "phrase",
+    "typeahead",
+    "boosts",
+    "_source",
+    "limit",
+    "explain",
+    "include_deleted",
+];
 
-    if let Err(e) = persist_index(&index, &entry.docs).await {
-        eprintln!("failed to save index {index}: {e}");
-        return Err(warp::reject());
-    }
+fn unknown_search_field(body: &Value) -> Option<String> {
+    strict_json::unknown_key(body, SEARCH_FIELDS)
+}
 
-    Ok(warp::reply::json(&json!({ "id": id })))
+/// Splits a comma-separated list of field paths, dropping empty entries.
+fn field_list(fields: &str) -> Vec<&str> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .collect()
 }
 
-async fn search_documents(index: String, params: SearchQuery, indexes: Indexes) -> Result<impl Reply, Rejection> {
-    let map = indexes.read().await;
-    if let Some(idx) = map.get(&index) {
-        let query = params.q.to_lowercase();
-        let results: Vec<_> = idx
-            .docs
-            .iter()
-            .filter(|d| serialize_contains(&d.data, &query))
-            .map(|d| json!({ "id": d.id, "document": d.data }))
-            .collect();
-        Ok(warp::reply::with_status(
-            warp::reply::json(&results),
-            warp::http::StatusCode::OK,
-        ))
-    } else {
-        Ok(warp::reply::with_status(
-            warp::reply::json(&json!({"error": "index not found"})),
-            warp::http::StatusCode::NOT_FOUND,
-        ))
+impl SearchQuery {
+    fn normalizer(&self) -> Normalizer {
+        Normalizer {
+            case_sensitive: self.case_sensitive,
+            fold: self.fold,
+        }
     }
-}
 
-fn serialize_contains(value: &Value, query: &str) -> bool {
-    value
-        .to_string()
-        .to_lowercase()
-        .contains(query)
-}
+    fn terms(&self, analyzer: &Analyzer) -> Vec<QueryTerm> {
+        self.terms_with(analyzer, self.normalizer())
+    }
+
+    fn terms_with(&self, analyzer: &Analyzer, normalizer: Normalizer) -> Vec<QueryTerm> {
+        if self.wildcard {
+            self.q
+                .split_whitespace()
+                .map(|pattern| QueryTerm::Wildcard(Wildcard::parse(pattern, normalizer)))
+                .collect()
+        } else if self.typeahead {
+            // Partly typed words are neither stemmed nor dropped as stopwords.
+            tokenize(&self.q, normalizer)
+                .iter()
+                .map(|prefix| QueryTerm::Typeahead(Typeahead::new(prefix)))
+                .collect()
+        } else {
+            analyzer
+                .tokens(&self.q, normalizer)
+                .into_iter()
+                .map(QueryTerm::Exact)
+                .collect()
+        }
+    }
 
-async fn load_indexes() -> Indexes {
-    let mut map = HashMap::new();
-    let data_dir = PathBuf::from("data");
-    if let Err(e) = fs::create_dir_all(&data_dir).await {
-        eprintln!("failed to create data dir: {e}");
-        return Arc::new(RwLock::new(map));
+    fn fields(&self) -> Option<Vec<&str>> {
+        self.fields.as_deref().map(field_list)
     }
 
-    let mut entries = match fs::read_dir(&data_dir).await {
-        Ok(e) => e,
-        Err(_) => return Arc::new(RwLock::new(map)),
-    };
+    fn source(&self) -> Option<Vec<&str>> {
+        self.source.as_deref().map(field_list)
+    }
 
-    while let Ok(Some(entry)) = entries.next_entry().await {
-        let path = entry.path();
-        if path.extension().and_then(|s| s.to_str()) == Some("bin") {
-            if let Ok(content) = fs::read(&path).await {
-                if let Ok(raw_docs) = bincode::deserialize::<Vec<PersistedDocument>>(&content) {
-                    if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
-                        let docs = raw_docs
-                            .into_iter()
-                            .filter_map(|d| {
-                                serde_json::from_slice(&d.data)
-                                    .ok()
-                                    .map(|value| Document { id: d.id, data: value })
-                            })
-                            .collect();
-                        map.insert(name.to_string(), Index { docs });
-                    }
+    /// Parsed `boosts`; malformed or negative entries are ignored.
+    fn boosts(&self) -> Vec<(&str, f64)> {
+        let Some(boosts) = self.boosts.as_deref() else {
+            return Vec::new();
+        };
+        boosts
+            .split(',')
+            .filter_map(|pair| {
+                let (field, boost) = pair.rsplit_once(':')?;
+                let boost: f64 = boost.trim().parse().ok()?;
+                (boost.is_finite() && boost >= 0.0).then_some((field.trim(), boost))
+            })
+            .collect()
+    }
+
+    /// The normalizer used to look terms up in the index, which only holds lowercase
+    /// tokens. A case-sensitive search finds a superset of candidates there and checks each
+    /// one against its own tokens.
+    fn lookup(&self) -> Normalizer {
+        Normalizer {
+            case_sensitive: false,
+            ..self.normalizer()
+        }
+    }
+
+    /// The indexed tokens each query term matches, looked up with [`SearchQuery::lookup`].
+    fn expand<'a>(&self, idx: &'a Index) -> Vec<Expansion<'a>> {
+        let lookup = self.lookup();
+        idx.expand(&self.terms_with(&idx.analyzer, lookup), lookup)
+    }
+
+    /// The query terms as written, one per entry of [`SearchQuery::expand`].
+    fn term_labels(&self, analyzer: &Analyzer) -> Vec<String> {
+        if self.wildcard {
+            self.q.split_whitespace().map(str::to_string).collect()
+        } else if self.typeahead {
+            tokenize(&self.q, self.lookup())
+        } else {
+            analyzer.tokens(&self.q, self.lookup())
+        }
+    }
+
+    /// Weighted occurrences that `boosts` add per token of the document at `pos`.
+    fn boost_counts(&self, idx: &Index, boosts: &[(&str, f64)], pos: usize) -> HashMap<String, f64> {
+        let mut extra = HashMap::new();
+        for (field, boost) in boosts {
+            if let Some(value) = get_path(&idx.docs[pos].data, field) {
+                // Counted once already; a boost of 2 counts each occurrence twice.
+                for token in idx.analyzer.value_tokens(value, Normalizer::default()) {
+                    *extra.entry(token).or_default() += boost - 1.0;
+                }
+            }
+        }
+        extra
+    }
+
+    /// Positions of the documents in `idx` matching the query with their BM25 scores, in
+    /// insertion order.
+    fn matching(&self, idx: &Index) -> Vec<(usize, f64)> {
+        let normalizer = self.normalizer();
+        let terms = self.terms(&idx.analyzer);
+        // A query made only of stopwords matches nothing rather than everything.
+        if terms.is_empty() && !self.wildcard && !tokenize(&self.q, normalizer).is_empty() {
+            return Vec::new();
+        }
+        let verify = self.lookup() != normalizer;
+        let expanded = self.expand(idx);
+        let positions = idx.matching(&expanded);
+        let fields = self.fields();
+        let boosts = self.boosts();
+        let check = fields.is_some() || verify || self.phrase;
+        let keep = |pos: usize| {
+            if !self.include_deleted && idx.is_deleted(pos) {
+                return None;
+            }
+            if check {
+                let data = &idx.docs[pos].data;
+                let values: Vec<_> = match &fields {
+                    Some(fields) => fields.iter().filter_map(|f| get_path(data, f)).collect(),
+                    None => vec![data],
+                };
+                let matched = if self.phrase {
+                    values
+                        .iter()
+                        .any(|v| idx.analyzer.contains_phrase(v, &terms, normalizer))
+                } else {
+                    values_contain(&values, &terms, &idx.analyzer, normalizer)
+                };
+                if !matched {
+                    return None;
                 }
             }
+            let extra = self.boost_counts(idx, &boosts, pos);
+            Some((pos, idx.score(pos, &expanded, &extra)))
+        };
+        // Parallel collects keep input order, so matches stay in insertion order.
+        if positions.len() >= PARALLEL_THRESHOLD {
+            positions.into_par_iter().filter_map(keep).collect()
+        } else {
+            positions.into_iter().filter_map(keep).collect()
         }
     }
+}
 
-    Arc::new(RwLock::new(map))
+fn cors(origins: &[String]) -> warp::cors::Builder {
+    let cors = warp::cors()
+        .allow_methods(["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
+        .allow_headers(["content-type", "content-encoding", "authorization", "x-api-key", request_id::HEADER])
+        .expose_headers([request_id::HEADER, TOTAL_COUNT_HEADER]);
+    if origins.iter().any(|o| o == "*") {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origins(origins.iter().map(String::as_str))
+    }
 }
 
-async fn persist_index(name: &str, docs: &Vec<Document>) -> Result<(), std::io::Error> {
-    let path = PathBuf::from("data").join(format!("{name}.bin"));
-    let raw: Vec<PersistedDocument> = docs
-        .iter()
-        .filter_map(|d| {
-            serde_json::to_vec(&d.data)
-                .ok()
-                .map(|data| PersistedDocument { id: d.id, data })
-        })
-        .collect();
-    let bytes = bincode::serialize(&raw)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    fs::write(path, bytes).await
+/// Turns rejections raised by the request filters into JSON errors; everything else keeps
+/// warp's default handling.
+async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if err.find::<auth::Unauthorized>().is_some() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&json!({"error": "missing or invalid API key"})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ),
+            "www-authenticate",
+            "Bearer".to_string(),
+        )
+        .into_response());
+    }
+    if let Some(limited) = err.find::<rate_limit::RateLimited>() {
+        // Retry-After is whole seconds; round up so clients never retry too early.
+        let secs = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&json!({"error": "rate limit exceeded"})),
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+            ),
+            "retry-after",
+            secs.to_string(),
+        )
+        .into_response());
+    }
+    if let Some(strict_json::UnknownField(field)) = err.find() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": format!("unknown field `{field}`")})),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+    if let Some(body::InvalidBody(message)) = err.find() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": format!("invalid request body: {message}")})),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    }
+    if let Some(body::UnsupportedEncoding(encoding)) = err.find() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": format!("unsupported content encoding {encoding:?}")})),
+            warp::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        )
+        .into_response());
+    }
+    if err.find::<warp::reject::PayloadTooLarge>().is_some() || err.find::<body::TooLarge>().is_some() {
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                warp::reply::json(&json!({"error": "request body too large"})),
+                warp::http::StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            // The unread body is still on the connection, so close it rather than reuse it.
+            "connection",
+            "close".to_string(),
+        )
+        .into_response());
+    }
+    Err(err)
+}
+
+/// Answers the rejections `handle_rejection` leaves alone, such as unknown paths and methods,
+/// with the status warp would pick, so these responses get a request id like any other.
+async fn handle_unmatched(err: Rejection) -> Result<warp::reply::Response, std::convert::Infallible> {
+    use warp::http::StatusCode;
+    use warp::reject::{InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader};
+    // When several routes reject, warp prefers anything over 405 and 405 over 404.
+    let known = err
+        .find::<warp::reject::UnsupportedMediaType>()
+        .map(|e| (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()))
+        .or_else(|| err.find::<LengthRequired>().map(|e| (StatusCode::LENGTH_REQUIRED, e.to_string())))
+        .or_else(|| err.find::<warp::cors::CorsForbidden>().map(|e| (StatusCode::FORBIDDEN, e.to_string())))
+        .or_else(|| err.find::<InvalidQuery>().map(|e| (StatusCode::BAD_REQUEST, e.to_string())))
+        .or_else(|| err.find::<InvalidHeader>().map(|e| (StatusCode::BAD_REQUEST, e.to_string())))
+        .or_else(|| err.find::<MissingHeader>().map(|e| (StatusCode::BAD_REQUEST, e.to_string())))
+        .or_else(|| err.find::<MethodNotAllowed>().map(|e| (StatusCode::METHOD_NOT_ALLOWED, e.to_string())));
+    let (status, message) = match known {
+        Some(known) => known,
+        None if err.is_not_found() => (StatusCode::NOT_FOUND, "not found".to_string()),
+        None => {
+            eprintln!("unhandled rejection: {err:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal server error".to_string())
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status).into_response())
+}
+
+async fn render_metrics(metrics: Arc<Metrics>, indexes: Indexes) -> Result<impl Reply, Rejection> {
+    let map = indexes.read().await;
+    Ok(warp::reply::with_header(
+        metrics.render(&map),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+async fn add_document(
+    index: String,
+    doc: Value,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut map = indexes.write().await;
+    let entry = map
+        .entry(index.clone())
+        .or_insert_with(|| Index::new(config.analyzer.clone()));
+    let id = match insert_documents(&config, &index, entry, vec![doc]).await {
+        Ok(ids) => ids[0],
+        Err(e) => {
+            eprintln!("failed to save index {index}: {e}");
+            return Err(warp::reject());
+        }
+    };
+
+    Ok(warp::reply::json(&json!({ "id": id })))
+}
+
+/// Logs `values` as new documents with a single sync, adds them to `entry` and returns
+/// their ids. Nothing is added if logging fails.
+async fn insert_documents(
+    config: &Config,
+    name: &str,
+    entry: &mut Index,
+    values: Vec<Value>,
+) -> Result<Vec<usize>, std::io::Error> {
+    let first = entry.next_id();
+    let docs: Vec<Document> = values
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| Document {
+            id: first + i,
+            version: 1,
+            data,
+        })
+        .collect();
+    let records = docs.iter().map(WalRecord::insert).collect::<Result<Vec<_>, _>>()?;
+    storage::append_log(config, name, &records).await?;
+    entry.wal_records += records.len();
+    let ids = docs.iter().map(|d| d.id).collect();
+    docs.into_iter().for_each(|doc| entry.insert(doc));
+    Ok(ids)
+}
+
+#[derive(Deserialize)]
+struct VersionParam {
+    /// The version the document must still have, like `If-Match`.
+    version: Option<u64>,
+}
+
+/// Whether a document at `version` satisfies an `If-Match` header, which may list several
+/// quoted versions or be `*`. Unparseable entries match nothing.
+fn if_match(header: &str, version: u64) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag.trim_matches('"').parse::<u64>().is_ok_and(|tag| tag == version)
+    })
+}
+
+fn etag(version: u64) -> String {
+    format!("\"{version}\"")
+}
+
+/// Merges `patch` into a document's top-level fields; `null` values remove the field.
+/// Returns the merged document, or `409` if `If-Match` or `version` name another version
+/// than the stored one.
+async fn patch_document(
+    index: String,
+    id: usize,
+    expected: VersionParam,
+    if_match_header: Option<String>,
+    patch: Value,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<warp::reply::Response, Rejection> {
+    let Value::Object(patch) = patch else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "patch must be a JSON object"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response());
+    };
+    let mut map = indexes.write().await;
+    let Some(entry) = map.get_mut(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+    // Documents stay in ascending id order.
+    let Some(pos) = entry
+        .docs
+        .binary_search_by_key(&id, |d| d.id)
+        .ok()
+        .filter(|pos| !entry.is_deleted(*pos))
+    else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "document not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+    let current = entry.docs[pos].version;
+    let stale = expected.version.is_some_and(|v| v != current)
+        || if_match_header.as_deref().is_some_and(|h| !if_match(h, current));
+    if stale {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "version conflict", "version": current})),
+            warp::http::StatusCode::CONFLICT,
+        )
+        .into_response());
+    }
+    let Value::Object(mut fields) = entry.docs[pos].data.clone() else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "document is not a JSON object"})),
+            warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .into_response());
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            fields.remove(&key);
+        } else {
+            fields.insert(key, value);
+        }
+    }
+
+    let updated = Document {
+        id,
+        version: current + 1,
+        data: Value::Object(fields),
+    };
+    let logged = match WalRecord::update(&updated) {
+        Ok(record) => storage::append_log(&config, &index, &[record]).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = logged {
+        eprintln!("failed to save index {index}: {e}");
+        return Err(warp::reject());
+    }
+    entry.wal_records += 1;
+    entry.update(HashMap::from([(id, updated.data.clone())]));
+
+    Ok(warp::reply::with_header(
+        warp::reply::json(&json!({"id": id, "version": updated.version, "document": updated.data})),
+        "etag",
+        etag(updated.version),
+    )
+    .into_response())
+}
+
+/// Milliseconds since the Unix epoch, the clock of tombstones.
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Soft-deletes a document: it disappears from searches and exports but stays recoverable
+/// with [`undelete_document`] until the retention period elapses.
+async fn delete_document(
+    index: String,
+    id: usize,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut map = indexes.write().await;
+    let Some(entry) = map.get_mut(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let live = entry
+        .docs
+        .binary_search_by_key(&id, |d| d.id)
+        .is_ok_and(|pos| !entry.is_deleted(pos));
+    if !live {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "document not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let at = now_millis();
+    if let Err(e) = storage::append_log(&config, &index, &[WalRecord::Tombstone(id, at)]).await {
+        eprintln!("failed to save index {index}: {e}");
+        return Err(warp::reject());
+    }
+    entry.wal_records += 1;
+    entry.mark_deleted([id], at);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({"id": id, "deleted_at": at})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Brings back a soft-deleted document that has not been purged yet.
+async fn undelete_document(
+    index: String,
+    id: usize,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut map = indexes.write().await;
+    let Some(entry) = map.get_mut(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let found = entry.docs.binary_search_by_key(&id, |d| d.id);
+    let Some(pos) = found.ok().filter(|_| entry.tombstones.contains_key(&id)) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "no deleted document with this id"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    if let Err(e) = storage::append_log(&config, &index, &[WalRecord::Undelete(id)]).await {
+        eprintln!("failed to save index {index}: {e}");
+        return Err(warp::reject());
+    }
+    entry.wal_records += 1;
+    entry.unmark_deleted(id);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "id": id,
+            "version": entry.docs[pos].version,
+            "document": entry.docs[pos].data
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Imports a CSV body with a header row, one document per row. Fields that parse as numbers
+/// are stored as numbers, everything else as strings. Rows that fail to parse are counted
+/// and skipped; the rest are logged with a single sync.
+async fn import_csv(
+    index: String,
+    body: warp::hyper::body::Bytes,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut reader = csv::Reader::from_reader(body.as_ref());
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": format!("invalid CSV header: {e}") })),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let mut values = Vec::new();
+    let mut failed = 0;
+    for record in reader.records() {
+        match record {
+            Ok(record) => {
+                let row: Map<String, Value> = headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(key, field)| (key.to_string(), csv_value(field)))
+                    .collect();
+                values.push(Value::Object(row));
+            }
+            Err(_) => failed += 1,
+        }
+    }
+
+    let mut map = indexes.write().await;
+    let entry = map
+        .entry(index.clone())
+        .or_insert_with(|| Index::new(config.analyzer.clone()));
+    let imported = match insert_documents(&config, &index, entry, values).await {
+        Ok(ids) => ids.len(),
+        Err(e) => {
+            eprintln!("failed to save index {index}: {e}");
+            return Err(warp::reject());
+        }
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "imported": imported, "failed": failed })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+fn csv_value(field: &str) -> Value {
+    if let Ok(n) = field.parse::<i64>() {
+        return Value::from(n);
+    }
+    match field.parse::<f64>() {
+        Ok(n) if n.is_finite() => Value::from(n),
+        _ => Value::String(field.to_string()),
+    }
+}
+
+/// Applies an NDJSON bulk body under one write lock and one log sync, reporting a result
+/// per action in the Elasticsearch `_bulk` response shape. Ids are always assigned by the
+/// server; an `_id` on `index`/`create` actions is ignored.
+async fn bulk_documents(
+    index: String,
+    body: warp::hyper::body::Bytes,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let started = Instant::now();
+    let ops = match std::str::from_utf8(&body)
+        .map_err(|e| e.to_string())
+        .and_then(bulk::parse)
+    {
+        Ok(ops) => ops,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({ "error": e })),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let mut map = indexes.write().await;
+    let entry = map
+        .entry(index.clone())
+        .or_insert_with(|| Index::new(config.analyzer.clone()));
+    let mut next_id = entry.next_id();
+    let mut live: HashSet<usize> = entry
+        .docs
+        .iter()
+        .map(|d| d.id)
+        .filter(|id| !entry.tombstones.contains_key(id))
+        .collect();
+    let at = now_millis();
+    let mut added = Vec::new();
+    let mut deleted = HashSet::new();
+    let mut records = Vec::new();
+    let mut items = Vec::with_capacity(ops.len());
+    let mut errors = false;
+    for op in ops {
+        let (action, result) = match op {
+            BulkOp::Index(Ok(data)) => {
+                let doc = Document {
+                    id: next_id,
+                    version: 1,
+                    data,
+                };
+                match WalRecord::insert(&doc) {
+                    Ok(record) => {
+                        records.push(record);
+                        live.insert(doc.id);
+                        next_id += 1;
+                        let result = json!({ "_id": doc.id, "status": 201, "result": "created" });
+                        added.push(doc);
+                        ("index", result)
+                    }
+                    Err(e) => ("index", json!({ "status": 400, "error": e.to_string() })),
+                }
+            }
+            BulkOp::Index(Err(e)) => ("index", json!({ "status": 400, "error": e })),
+            BulkOp::Delete(Some(id)) if live.remove(&id) => {
+                records.push(WalRecord::Tombstone(id, at));
+                deleted.insert(id);
+                ("delete", json!({ "_id": id, "status": 200, "result": "deleted" }))
+            }
+            BulkOp::Delete(Some(id)) => {
+                ("delete", json!({ "_id": id, "status": 404, "result": "not_found" }))
+            }
+            BulkOp::Delete(None) => {
+                ("delete", json!({ "status": 400, "error": "missing or invalid _id" }))
+            }
+            BulkOp::Unsupported(action) => {
+                let error = format!("{action} actions are not supported");
+                errors = true;
+                items.push(json!({ action: { "status": 400, "error": error } }));
+                continue;
+            }
+        };
+        errors |= result.get("error").is_some();
+        items.push(json!({ action: result }));
+    }
+
+    if !records.is_empty()
+        && let Err(e) = storage::append_log(&config, &index, &records).await
+    {
+        eprintln!("failed to save index {index}: {e}");
+        return Err(warp::reject());
+    }
+    entry.wal_records += records.len();
+    added.into_iter().for_each(|doc| entry.insert(doc));
+    entry.mark_deleted(deleted, at);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "took": started.elapsed().as_millis() as u64,
+            "errors": errors,
+            "items": items,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `json` (default) or `csv`.
+    format: Option<String>,
+}
+
+/// Dumps every document of an index. JSON uses the same `{id, document}` shape as search
+/// hits. CSV has an `id` column followed by the union of top-level keys in first-seen
+/// order; nested values are written as JSON and missing ones as empty cells.
+async fn export_documents(
+    index: String,
+    params: ExportQuery,
+    indexes: Indexes,
+) -> Result<warp::reply::Response, Rejection> {
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+
+    match params.format.as_deref().unwrap_or("json") {
+        "json" => {
+            let docs: Vec<_> = idx
+                .live_docs()
+                .iter()
+                .map(|d| json!({ "id": d.id, "version": d.version, "document": d.data }))
+                .collect();
+            Ok(warp::reply::json(&docs).into_response())
+        }
+        "csv" => match export_csv(&idx.live_docs()) {
+            Ok(body) => Ok(warp::reply::with_header(body, "content-type", "text/csv").into_response()),
+            Err(e) => {
+                eprintln!("failed to export index {index}: {e}");
+                Err(warp::reject())
+            }
+        },
+        other => Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("unknown export format {other:?}") })),
+            warp::http::StatusCode::BAD_REQUEST,
+        )
+        .into_response()),
+    }
+}
+
+fn export_csv(docs: &[Document]) -> Result<Vec<u8>, csv::Error> {
+    let mut columns: Vec<&str> = Vec::new();
+    let mut seen = HashSet::new();
+    for doc in docs {
+        for key in doc.data.as_object().into_iter().flat_map(|obj| obj.keys()) {
+            if seen.insert(key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(std::iter::once("id").chain(columns.iter().copied()))?;
+    for doc in docs {
+        let cells = columns.iter().map(|column| match doc.data.get(column) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(other) => other.to_string(),
+        });
+        writer.write_record(std::iter::once(doc.id.to_string()).chain(cells))?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+#[derive(Deserialize)]
+struct ScrollParams {
+    /// Seconds to keep a scroll cursor open between pages; a plain search when omitted.
+    scroll: Option<u64>,
+    /// Hits per scroll page, limited like a search's `limit`.
+    size: Option<usize>,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn search_documents(
+    index: String,
+    params: SearchQuery,
+    scroll: ScrollParams,
+    accept: Option<String>,
+    indexes: Indexes,
+    scrolls: Arc<Scrolls>,
+    cache: Option<Arc<SearchCache>>,
+    config: Arc<Config>,
+) -> Result<warp::reply::Response, Rejection> {
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+    let Some(keep_alive) = scroll.scroll else {
+        let limit = config.limit(params.limit);
+        if accept.as_deref().is_some_and(accepts_ndjson) {
+            let mut ranked = ranked_matches(idx, &params);
+            let total = ranked.len();
+            ranked.truncate(limit);
+            let ranked = ranked.into_iter().map(|(pos, score)| (idx.docs[pos].id, score)).collect();
+            let response = ndjson_response(indexes.clone(), index, params, ranked);
+            return Ok(with_total_count(response, total));
+        }
+        // Explanations are for looking into one ranking and are always computed afresh.
+        let Some(cache) = cache.filter(|_| !params.explain) else {
+            let (total, hits) = search_hits(idx, &params, limit);
+            return Ok(with_total_count(warp::reply::json(&hits).into_response(), total));
+        };
+        let key = format!("{limit} {}", serde_json::to_string(&params).unwrap_or_default());
+        let (body, total) = match cache.get(&index, &key, idx.generation) {
+            Some(cached) => cached,
+            None => {
+                let (total, hits) = search_hits(idx, &params, limit);
+                let body = serde_json::to_string(&hits).unwrap_or_default();
+                cache.put(&index, &key, idx.generation, body.clone(), total);
+                (body, total)
+            }
+        };
+        let response = warp::reply::with_header(body, "content-type", "application/json").into_response();
+        return Ok(with_total_count(response, total));
+    };
+
+    let ranked: Vec<_> = ranked_matches(idx, &params)
+        .into_iter()
+        .map(|(pos, score)| (idx.docs[pos].id, score))
+        .collect();
+    let total = ranked.len();
+    let size = config.limit(scroll.size);
+    let Some((id, page)) = scrolls.open(index, params, ranked, size, Duration::from_secs(keep_alive)) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "too many open scrolls"})),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        )
+        .into_response());
+    };
+    let hits = page_hits(idx, &page.query, &page.hits);
+    let response = warp::reply::json(&json!({ "scroll_id": page.more.then_some(id), "hits": hits }));
+    Ok(with_total_count(response.into_response(), total))
+}
+
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Adds the `X-Total-Count` header: how many documents matched before the hits were cut to
+/// the limit.
+fn with_total_count(mut response: warp::reply::Response, total: usize) -> warp::reply::Response {
+    response.headers_mut().insert(TOTAL_COUNT_HEADER, total.into());
+    response
+}
+
+/// Whether an `Accept` header lists `application/x-ndjson`.
+fn accepts_ndjson(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        let media = media.split(';').next().unwrap_or_default().trim();
+        media.eq_ignore_ascii_case("application/x-ndjson")
+    })
+}
+
+/// Hits rendered under one read lock while streaming NDJSON.
+const NDJSON_CHUNK: usize = 100;
+
+/// Sends the ranked (document id, score) pairs in `hits` as newline-delimited JSON. Hits are
+/// rendered a chunk at a time as the client reads them, taking the read lock per chunk, so
+/// only one chunk of rendered documents is held at once. Documents deleted before their
+/// chunk is rendered are skipped, as on a scroll page.
+fn ndjson_response(
+    indexes: Indexes,
+    index: String,
+    params: SearchQuery,
+    hits: Vec<(usize, f64)>,
+) -> warp::reply::Response {
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        for chunk in hits.chunks(NDJSON_CHUNK) {
+            let mut lines = Vec::new();
+            {
+                let map = indexes.read().await;
+                let Some(idx) = map.get(&index) else {
+                    break;
+                };
+                for hit in page_hits(idx, &params, chunk) {
+                    let _ = serde_json::to_writer(&mut lines, &hit);
+                    lines.push(b'\n');
+                }
+            }
+            // Fails once the client has gone away.
+            if sender.send_data(lines.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut response = warp::reply::Response::new(body);
+    response.headers_mut().insert(
+        warp::http::header::CONTENT_TYPE,
+        warp::http::HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
+
+#[derive(Deserialize)]
+struct ScrollId {
+    scroll_id: String,
+}
+
+/// Returns the next page of a scroll cursor. `scroll_id` is `null` on the last page.
+async fn scroll_next(
+    params: ScrollId,
+    indexes: Indexes,
+    scrolls: Arc<Scrolls>,
+) -> Result<impl Reply, Rejection> {
+    let Some(page) = scrolls.next(&params.scroll_id) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "scroll not found or expired"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&page.index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let hits = page_hits(idx, &page.query, &page.hits);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "scroll_id": page.more.then_some(params.scroll_id),
+            "hits": hits,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Renders a scroll page of (id, score) pairs, skipping documents deleted since the search.
+fn page_hits(idx: &Index, params: &SearchQuery, hits: &[(usize, f64)]) -> Vec<Value> {
+    let matches = hits
+        .iter()
+        .filter_map(|(id, score)| {
+            // Documents stay in ascending id order.
+            let pos = idx.docs.binary_search_by_key(id, |d| d.id).ok()?;
+            (params.include_deleted || !idx.is_deleted(pos)).then_some((pos, *score))
+        })
+        .collect();
+    render_hits(idx, params, matches)
+}
+
+/// Positions and scores of the documents matching a search, most relevant first.
+fn ranked_matches(idx: &Index, params: &SearchQuery) -> Vec<(usize, f64)> {
+    let mut matches = params.matching(idx);
+    // Ties go to the lower id, which is the lower position as ids ascend with position, so
+    // pages never overlap or skip documents.
+    matches.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    matches
+}
+
+/// Runs a search against `idx` and builds its hits, most relevant first, up to `limit`,
+/// along with the number of documents that matched.
+fn search_hits(idx: &Index, params: &SearchQuery, limit: usize) -> (usize, Vec<Value>) {
+    let mut matches = ranked_matches(idx, params);
+    let total = matches.len();
+    matches.truncate(limit);
+    (total, render_hits(idx, params, matches))
+}
+
+/// Builds the hit objects for `matches`, with highlights and explanations when the search
+/// asks for them.
+fn render_hits(idx: &Index, params: &SearchQuery, matches: Vec<(usize, f64)>) -> Vec<Value> {
+    let terms = params.terms(&idx.analyzer);
+    let normalizer = params.normalizer();
+    let tag = params.highlight_tag.as_deref().unwrap_or("em");
+    let fields = params.fields();
+    let source = params.source();
+    let (expanded, labels, boosts) = if params.explain {
+        (params.expand(idx), params.term_labels(&idx.analyzer), params.boosts())
+    } else {
+        Default::default()
+    };
+    let hit = |(pos, score): (usize, f64)| {
+        let d = &idx.docs[pos];
+        let mut hit = match source.as_deref() {
+            Some(["false"]) => json!({ "id": d.id, "version": d.version, "score": score }),
+            Some(paths) => json!({
+                "id": d.id,
+                "version": d.version,
+                "score": score,
+                "document": select_paths(&d.data, paths)
+            }),
+            None => json!({ "id": d.id, "version": d.version, "score": score, "document": d.data }),
+        };
+        if params.highlight {
+            let mut marked = Map::new();
+            match &fields {
+                Some(fields) => {
+                    for field in fields {
+                        if let Some(v) = get_path(&d.data, field) {
+                            collect_highlights(v, field, &terms, &idx.analyzer, normalizer, tag, &mut marked);
+                        }
+                    }
+                }
+                None => collect_highlights(&d.data, "", &terms, &idx.analyzer, normalizer, tag, &mut marked),
+            }
+            hit["highlights"] = Value::Object(marked);
+        }
+        if let Some(at) = idx.tombstones.get(&d.id) {
+            hit["deleted_at"] = json!(at);
+        }
+        if params.explain {
+            let extra = params.boost_counts(idx, &boosts, pos);
+            hit["explanation"] = idx.explain(pos, &expanded, &extra, &labels);
+        }
+        hit
+    };
+    if matches.len() >= PARALLEL_THRESHOLD {
+        matches.into_par_iter().map(hit).collect()
+    } else {
+        matches.into_iter().map(hit).collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct GeoQuery {
+    lat: f64,
+    lon: f64,
+    /// Dotted path of the `{"lat", "lon"}` object; the document itself when omitted.
+    field: Option<String>,
+    /// Only documents within this many kilometres.
+    radius_km: Option<f64>,
+    /// Most hits to return; see [`Config::limit`].
+    limit: Option<usize>,
+}
+
+/// Documents nearest to a point, closest first, with their distance in km as `score`.
+async fn search_geo(
+    index: String,
+    params: GeoQuery,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let radius = params.radius_km.unwrap_or(f64::INFINITY);
+    let distance = |pos: usize| {
+        if idx.is_deleted(pos) {
+            return None;
+        }
+        let data = &idx.docs[pos].data;
+        let point = match &params.field {
+            Some(field) => get_path(data, field)?,
+            None => data,
+        };
+        let (lat, lon) = geo_point(point)?;
+        let km = haversine_km(params.lat, params.lon, lat, lon);
+        (km <= radius).then_some((pos, km))
+    };
+    let mut matches: Vec<_> = if idx.docs.len() >= PARALLEL_THRESHOLD {
+        (0..idx.docs.len()).into_par_iter().filter_map(distance).collect()
+    } else {
+        (0..idx.docs.len()).filter_map(distance).collect()
+    };
+    matches.sort_unstable_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    matches.truncate(config.limit(params.limit));
+    let hits: Vec<_> = matches
+        .into_iter()
+        .map(|(pos, km)| {
+            let d = &idx.docs[pos];
+            json!({ "id": d.id, "version": d.version, "score": km, "document": d.data })
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&hits),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct CrossSearchQuery {
+    /// Comma-separated index names; every index when omitted.
+    indexes: Option<String>,
+}
+
+/// Searches several indexes and merges their hits by score, tagging each with its index.
+/// Every index uses the same analyzer and BM25, but IDF and average document length come
+/// from each index's own statistics, so the merged order is only approximately by relevance.
+async fn cross_search(
+    cross: CrossSearchQuery,
+    params: SearchQuery,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let map = indexes.read().await;
+    let names: Vec<&str> = match &cross.indexes {
+        Some(names) => names.split(',').map(str::trim).filter(|n| !n.is_empty()).collect(),
+        None => {
+            let mut names: Vec<&str> = map.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            names
+        }
+    };
+    let limit = config.limit(params.limit);
+    let mut hits = Vec::new();
+    let mut total = 0;
+    for name in names {
+        let Some(idx) = map.get(name) else {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": format!("index not found: {name}")})),
+                warp::http::StatusCode::NOT_FOUND,
+            )
+            .into_response());
+        };
+        // No index can contribute more than `limit` hits to the merged list.
+        let (matched, index_hits) = search_hits(idx, &params, limit);
+        total += matched;
+        for mut hit in index_hits {
+            hit["index"] = json!(name);
+            hits.push(hit);
+        }
+    }
+    let score = |hit: &Value| hit["score"].as_f64().unwrap_or(0.0);
+    let key = |hit: &Value| (hit["index"].as_str().map(str::to_owned), hit["id"].as_u64());
+    // Ties go by index name and then id, whatever order the indexes were listed in.
+    hits.sort_by(|a, b| score(b).total_cmp(&score(a)).then_with(|| key(a).cmp(&key(b))));
+    hits.truncate(limit);
+
+    Ok(with_total_count(warp::reply::json(&hits).into_response(), total))
+}
+
+#[derive(Deserialize)]
+struct MultiSearchItem {
+    index: String,
+    query: SearchQuery,
+}
+
+fn unknown_msearch_field(body: &Value) -> Option<String> {
+    body.as_array()?.iter().enumerate().find_map(|(i, item)| {
+        if let Some(key) = strict_json::unknown_key(item, &["index", "query"]) {
+            return Some(format!("[{i}].{key}"));
+        }
+        let key = unknown_search_field(item.get("query")?)?;
+        Some(format!("[{i}].query.{key}"))
+    })
+}
+
+/// Runs several searches under one read lock. Each result is the array `/search` would
+/// return, or an error object for a missing index, in request order.
+async fn multi_search(
+    items: Vec<MultiSearchItem>,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let map = indexes.read().await;
+    let responses: Vec<Value> = items
+        .iter()
+        .map(|item| match map.get(&item.index) {
+            Some(idx) => Value::Array(search_hits(idx, &item.query, config.limit(item.query.limit)).1),
+            None => json!({"error": "index not found"}),
+        })
+        .collect();
+    Ok(warp::reply::json(&responses))
+}
+
+/// Deletes every document matching a search (the same parameters as `/search`, as a JSON
+/// body) with one log sync.
+async fn delete_by_query(
+    index: String,
+    params: SearchQuery,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut map = indexes.write().await;
+    let Some(entry) = map.get_mut(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    let ids: HashSet<usize> = params
+        .matching(entry)
+        .into_iter()
+        .filter(|(pos, _)| !entry.is_deleted(*pos))
+        .map(|(pos, _)| entry.docs[pos].id)
+        .collect();
+    if !ids.is_empty() {
+        let at = now_millis();
+        let records: Vec<_> = ids.iter().map(|id| WalRecord::Tombstone(*id, at)).collect();
+        if let Err(e) = storage::append_log(&config, &index, &records).await {
+            eprintln!("failed to save index {index}: {e}");
+            return Err(warp::reject());
+        }
+        entry.wal_records += records.len();
+        entry.mark_deleted(ids.iter().copied(), at);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "deleted": ids.len() })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct UpdateByQuery {
+    #[serde(flatten)]
+    query: SearchQuery,
+    /// Top-level fields merged into every matching document.
+    doc: Map<String, Value>,
+}
+
+fn unknown_update_field(body: &Value) -> Option<String> {
+    strict_json::unknown_key(body, &[SEARCH_FIELDS, &["doc"]].concat())
+}
+
+/// Merges `doc` into every object document matching a search (the same parameters as
+/// `/search`, as a JSON body) with one log sync.
+async fn update_by_query(
+    index: String,
+    body: UpdateByQuery,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let mut map = indexes.write().await;
+    let Some(entry) = map.get_mut(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    let mut changes = HashMap::new();
+    let mut records = Vec::new();
+    for (pos, _) in body.query.matching(entry) {
+        if entry.is_deleted(pos) {
+            continue;
+        }
+        let doc = &entry.docs[pos];
+        let Value::Object(fields) = &doc.data else {
+            continue;
+        };
+        let mut fields = fields.clone();
+        fields.extend(body.doc.clone());
+        let updated = Document {
+            id: doc.id,
+            version: doc.version + 1,
+            data: Value::Object(fields),
+        };
+        match WalRecord::update(&updated) {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                eprintln!("failed to save index {index}: {e}");
+                return Err(warp::reject());
+            }
+        }
+        changes.insert(updated.id, updated.data);
+    }
+    let updated = changes.len();
+    if !records.is_empty() {
+        if let Err(e) = storage::append_log(&config, &index, &records).await {
+            eprintln!("failed to save index {index}: {e}");
+            return Err(warp::reject());
+        }
+        entry.wal_records += records.len();
+        entry.update(changes);
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "updated": updated })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[derive(Deserialize)]
+struct Reindex {
+    source: String,
+    dest: String,
+    /// Copies only the documents this search matches; every document when omitted.
+    query: Option<SearchQuery>,
+}
+
+fn unknown_reindex_field(body: &Value) -> Option<String> {
+    if let Some(key) = strict_json::unknown_key(body, &["source", "dest", "query"]) {
+        return Some(key);
+    }
+    let key = unknown_search_field(body.get("query")?)?;
+    Some(format!("query.{key}"))
+}
+
+/// Copies the documents of `source` matching `query` into `dest`, creating it if needed,
+/// with one log sync. The copies get new ids in `dest`; `source` is left as it is.
+async fn reindex(body: Reindex, indexes: Indexes, config: Arc<Config>) -> Result<impl Reply, Rejection> {
+    if body.source == body.dest {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "source and dest must be different indexes"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    let mut map = indexes.write().await;
+    let Some(source) = map.get(&body.source) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": format!("index not found: {}", body.source)})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    let values: Vec<Value> = match &body.query {
+        Some(query) => {
+            // Copy in source order rather than rank order.
+            let mut positions: Vec<usize> =
+                query.matching(source).into_iter().map(|(pos, _)| pos).collect();
+            positions.retain(|pos| !source.is_deleted(*pos));
+            positions.sort_unstable();
+            positions.into_iter().map(|pos| source.docs[pos].data.clone()).collect()
+        }
+        None => source.live_docs().iter().map(|doc| doc.data.clone()).collect(),
+    };
+
+    let count = values.len();
+    if count > 0 {
+        let entry = map
+            .entry(body.dest.clone())
+            .or_insert_with(|| Index::new(config.analyzer.clone()));
+        if let Err(e) = insert_documents(&config, &body.dest, entry, values).await {
+            eprintln!("failed to save index {}: {e}", body.dest);
+            return Err(warp::reject());
+        }
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "reindexed": count })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Whether every term matches a token, analyzed with `normalizer`, somewhere within `values`.
+fn values_contain(values: &[&Value], terms: &[QueryTerm], analyzer: &Analyzer, normalizer: Normalizer) -> bool {
+    let tokens: HashSet<String> = values
+        .iter()
+        .flat_map(|v| analyzer.value_tokens(v, normalizer))
+        .collect();
+    terms.iter().all(|t| tokens.iter().any(|token| t.matches(token)))
+}
+
+async fn index_stats(index: String, indexes: Indexes) -> Result<impl Reply, Rejection> {
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({
+            "documents": idx.live_count(),
+            "deleted": idx.tombstones.len(),
+            "next_id": idx.next_id(),
+            "terms": idx.postings.len(),
+            "tokens": idx.total_length,
+            "pending_log_records": idx.wal_records,
+            "approximate_size_bytes": idx.approximate_size(),
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn snapshot_index(index: String, indexes: Indexes) -> Result<warp::reply::Response, Rejection> {
+    let map = indexes.read().await;
+    let Some(idx) = map.get(&index) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&json!({"error": "index not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+    match storage::encode_snapshot(&idx.live_docs(), &HashMap::new(), idx.next_id()) {
+        Ok(bytes) => Ok(warp::reply::with_header(bytes, "content-type", "application/octet-stream")
+            .into_response()),
+        Err(e) => {
+            eprintln!("failed to snapshot index {index}: {e}");
+            Err(warp::reject())
+        }
+    }
+}
+
+async fn restore_index(
+    index: String,
+    body: warp::hyper::body::Bytes,
+    indexes: Indexes,
+    config: Arc<Config>,
+) -> Result<impl Reply, Rejection> {
+    let snapshot = match storage::decode_snapshot(&body) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&json!({"error": format!("invalid snapshot: {e}")})),
+                warp::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let _compacting = storage::lock_compactions().await;
+    let mut map = indexes.write().await;
+    let saved = storage::compact_index(&config, &index, &snapshot.docs, &snapshot.tombstones, snapshot.next_id);
+    if let Err(e) = saved.await {
+        eprintln!("failed to save index {index}: {e}");
+        return Err(warp::reject());
+    }
+    let mut restored = Index::new(config.analyzer.clone());
+    snapshot.docs.into_iter().for_each(|doc| restored.insert(doc));
+    restored.next_id = restored.next_id.max(snapshot.next_id);
+    restored.tombstones = snapshot.tombstones;
+    let count = restored.live_count();
+    map.insert(index, restored);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&json!({ "restored": count })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use warp::hyper::body::{to_bytes, Bytes};
+
+    fn doc(id: usize, text: &str) -> Document {
+        Document { id, version: 1, data: json!({ "t": text }) }
+    }
+
+    fn index_of(docs: Vec<Document>) -> Index {
+        let mut index = Index::new(Arc::default());
+        docs.into_iter().for_each(|doc| index.insert(doc));
+        index
+    }
+
+    fn corpus(count: usize) -> Vec<Document> {
+        (1..=count)
+            .map(|id| doc(id, &format!("rust search engine word{} common common", id % 7)))
+            .collect()
+    }
+
+    /// Ids and scores of the documents matching `q`, best first.
+    fn search(index: &Index, q: &str) -> Vec<(usize, f64)> {
+        let params: SearchQuery = serde_json::from_value(json!({ "q": q })).unwrap();
+        ranked_matches(index, &params)
+            .into_iter()
+            .map(|(pos, score)| (index.docs[pos].id, score))
+            .collect()
+    }
+
+    /// Asserts that `index` holds exactly what inserting its documents afresh would build.
+    fn assert_matches_rebuild(index: &Index) {
+        let mut rebuilt = index_of(index.docs.clone());
+        rebuilt.tombstones = index.tombstones.clone();
+        let postings = |index: &Index| -> BTreeMap<String, (Vec<usize>, Vec<u32>)> {
+            index
+                .postings
+                .iter()
+                .map(|(token, p)| (token.clone(), (p.positions.clone(), p.counts.clone())))
+                .collect()
+        };
+        assert_eq!(postings(index), postings(&rebuilt));
+        assert_eq!(index.lengths, rebuilt.lengths);
+        assert_eq!(index.total_length, rebuilt.total_length);
+        for q in ["rust", "common", "word3", "fresh", "rust word1"] {
+            assert_eq!(search(index, q), search(&rebuilt, q), "scores for {q:?}");
+        }
+    }
+
+    #[test]
+    fn incremental_update_matches_a_rebuild() {
+        let mut index = index_of(corpus(200));
+        let generation = index.generation;
+        // Drops `search` and `word3`, adds `fresh`, changes the count of `common`.
+        index.update(HashMap::from([(10, json!({ "t": "rust fresh fresh common" }))]));
+        assert_matches_rebuild(&index);
+        assert_ne!(index.generation, generation);
+        assert_eq!(index.docs[9].version, 2);
+        assert_eq!(search(&index, "fresh")[0].0, 10);
+        assert!(search(&index, "search").iter().all(|(id, _)| *id != 10));
+
+        // A token only the document held leaves the vocabulary.
+        index.update(HashMap::from([(10, json!({ "t": "rust" }))]));
+        assert_matches_rebuild(&index);
+        assert!(!index.postings.contains_key("fresh"));
+
+        index.update(HashMap::from([(1, json!({})), (200, json!({ "t": "common" }))]));
+        assert_matches_rebuild(&index);
+        assert_eq!(index.lengths[0], 0);
+    }
+
+    #[test]
+    fn mass_update_rebuilds_with_the_same_result() {
+        let mut index = index_of(corpus(20));
+        let changes = (1..=10).map(|id| (id, json!({ "t": format!("fresh {id}") }))).collect();
+        index.update(changes);
+        assert_matches_rebuild(&index);
+        assert!(index.docs[..10].iter().all(|d| d.version == 2));
+        assert!(index.docs[10..].iter().all(|d| d.version == 1));
+        assert_eq!(search(&index, "fresh").len(), 10);
+    }
+
+    #[test]
+    fn scores_after_a_purge_match_a_fresh_index() {
+        let mut index = index_of(corpus(50));
+        index.mark_deleted([3, 4, 20], 100);
+        index.mark_deleted([30], 300);
+        index.purge_deleted_before(200);
+        assert_eq!(index.docs.len(), 47);
+        assert_eq!(index.tombstones, HashMap::from([(30, 300)]));
+        assert_matches_rebuild(&index);
+
+        // Purged documents no longer count towards document frequencies or lengths.
+        let remaining: Vec<_> = corpus(50).into_iter().filter(|d| ![3, 4, 20].contains(&d.id)).collect();
+        let mut fresh = index_of(remaining);
+        fresh.mark_deleted([30], 300);
+        assert_eq!(search(&index, "rust word3"), search(&fresh, "rust word3"));
+        assert_ne!(search(&index, "rust word3"), search(&index_of(corpus(50)), "rust word3"));
+    }
+
+    #[test]
+    fn highlights_escape_the_document_text() {
+        let index = index_of(vec![doc(1, "<b>rust</b> & \"rust\"")]);
+        let params: SearchQuery = serde_json::from_value(json!({ "q": "rust", "highlight": true })).unwrap();
+        let (_, hits) = search_hits(&index, &params, 1);
+        assert_eq!(
+            hits[0]["highlights"]["t"],
+            json!(["&lt;b&gt;<em>rust</em>&lt;/b&gt; &amp; &quot;<em>rust</em>&quot;"])
+        );
+    }
+
+    #[test]
+    fn search_fields_list_every_search_parameter() {
+        // A struct literal, so a new field does not compile until it is added here too.
+        let query = SearchQuery {
+            q: String::new(),
+            highlight: true,
+            highlight_tag: Some("em".into()),
+            fields: Some("title".into()),
+            wildcard: true,
+            case_sensitive: true,
+            fold: true,
+            phrase: true,
+            typeahead: true,
+            boosts: Some("title:2".into()),
+            source: Some("title".into()),
+            limit: Some(1),
+            explain: true,
+            include_deleted: true,
+        };
+        let serialized = serde_json::to_value(&query).unwrap();
+        let mut keys: Vec<&str> = serialized.as_object().unwrap().keys().map(String::as_str).collect();
+        let mut known = SEARCH_FIELDS.to_vec();
+        keys.sort_unstable();
+        known.sort_unstable();
+        assert_eq!(keys, known);
+
+        let spec = openapi::spec();
+        let body = &spec["paths"]["/indexes/{index}/_delete_by_query"]["post"]["requestBody"];
+        let mut documented: Vec<&str> = body["content"]["application/json"]["schema"]["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        documented.sort_unstable();
+        assert_eq!(documented, known);
+    }
+
+    fn bulk_body(count: usize) -> Bytes {
+        (0..count)
+            .map(|n| format!("{{\"index\":{{}}}}\n{{\"n\":{n}}}\n"))
+            .collect::<String>()
+            .into()
+    }
+
+    async fn created_ids(reply: impl Reply) -> Vec<usize> {
+        let body = to_bytes(reply.into_response().into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["errors"], json!(false));
+        body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["index"]["_id"].as_u64().unwrap() as usize)
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn simultaneous_bulk_requests_get_distinct_ids() {
+        let dir = std::env::temp_dir().join(format!("blackbox-bulk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = Config::from_env();
+        config.data_dir = dir.clone();
+        let config = Arc::new(config);
+        let indexes = storage::load_indexes(&config).await;
+
+        let first = tokio::spawn(bulk_documents("books".into(), bulk_body(200), indexes.clone(), config.clone()));
+        let second = tokio::spawn(bulk_documents("books".into(), bulk_body(200), indexes.clone(), config.clone()));
+        let (first, second) = tokio::join!(first, second);
+        let mut ids = created_ids(first.unwrap().unwrap()).await;
+        ids.extend(created_ids(second.unwrap().unwrap()).await);
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=400).collect::<Vec<_>>());
+
+        // The log replays to the same documents.
+        let reloaded = storage::load_indexes(&config).await;
+        let reloaded = reloaded.read().await;
+        let reloaded: Vec<usize> = reloaded["books"].docs.iter().map(|d| d.id).collect();
+        assert_eq!(reloaded, ids);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
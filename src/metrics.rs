@@ -0,0 +1,141 @@
+//! Request metrics collected from the access-log hook and rendered in the Prometheus text
+//! exposition format.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::Index;
+
+/// Upper bounds, in seconds, of the search latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// (method, route, status) -> count
+    requests: BTreeMap<(&'static str, &'static str, u16), u64>,
+    /// Non-cumulative counts per bucket; the last slot counts searches slower than every bound.
+    search_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    search_count: u64,
+    search_seconds: f64,
+}
+
+/// Maps a request path onto the fixed name of the route serving it, e.g.
+/// `/indexes/books/documents/7` becomes `/indexes/{index}/documents/{id}`, whatever the
+/// status of the response. Paths that match no route are counted as `other` so that probing
+/// random URLs cannot grow the label set.
+fn route_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [""] => "/",
+        ["health"] => "/health",
+        ["ready"] => "/ready",
+        ["metrics"] => "/metrics",
+        ["openapi.json"] => "/openapi.json",
+        ["_scroll"] => "/_scroll",
+        ["_search"] => "/_search",
+        ["_msearch"] => "/_msearch",
+        ["_reindex"] => "/_reindex",
+        ["indexes", _, "documents"] => "/indexes/{index}/documents",
+        ["indexes", _, "documents", _] => "/indexes/{index}/documents/{id}",
+        ["indexes", _, "documents", _, "_undelete"] => "/indexes/{index}/documents/{id}/_undelete",
+        ["indexes", _, "_bulk"] => "/indexes/{index}/_bulk",
+        ["indexes", _, "_import_csv"] => "/indexes/{index}/_import_csv",
+        ["indexes", _, "_export"] => "/indexes/{index}/_export",
+        ["indexes", _, "_delete_by_query"] => "/indexes/{index}/_delete_by_query",
+        ["indexes", _, "_update_by_query"] => "/indexes/{index}/_update_by_query",
+        ["indexes", _, "search"] => "/indexes/{index}/search",
+        ["indexes", _, "search_geo"] => "/indexes/{index}/search_geo",
+        ["indexes", _, "_stats"] => "/indexes/{index}/_stats",
+        ["indexes", _, "_snapshot"] => "/indexes/{index}/_snapshot",
+        ["indexes", _, "_restore"] => "/indexes/{index}/_restore",
+        _ => "other",
+    }
+}
+
+/// The method label; methods the server never routes are counted as `other`, like paths.
+fn method_label(method: &warp::http::Method) -> &'static str {
+    match method.as_str() {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        _ => "other",
+    }
+}
+
+impl Metrics {
+    pub fn observe(&self, info: &warp::log::Info) {
+        let route = route_label(info.path());
+        let elapsed = info.elapsed().as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+        if route.ends_with("/search") {
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .position(|bound| elapsed <= *bound)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            inner.search_buckets[bucket] += 1;
+            inner.search_count += 1;
+            inner.search_seconds += elapsed;
+        }
+        let key = (method_label(info.method()), route, info.status().as_u16());
+        *inner.requests.entry(key).or_default() += 1;
+    }
+
+    pub fn render(&self, indexes: &HashMap<String, Index>) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP blackbox_http_requests_total HTTP requests by method, route and status.\n");
+        out.push_str("# TYPE blackbox_http_requests_total counter\n");
+        for ((method, route, status), count) in &inner.requests {
+            let _ = writeln!(
+                out,
+                "blackbox_http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape_label(route)
+            );
+        }
+
+        out.push_str("# HELP blackbox_search_latency_seconds Latency of search requests.\n");
+        out.push_str("# TYPE blackbox_search_latency_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&inner.search_buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "blackbox_search_latency_seconds_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "blackbox_search_latency_seconds_bucket{{le=\"+Inf\"}} {}", inner.search_count);
+        let _ = writeln!(out, "blackbox_search_latency_seconds_sum {}", inner.search_seconds);
+        let _ = writeln!(out, "blackbox_search_latency_seconds_count {}", inner.search_count);
+
+        out.push_str("# HELP blackbox_indexes Number of indexes.\n");
+        out.push_str("# TYPE blackbox_indexes gauge\n");
+        let _ = writeln!(out, "blackbox_indexes {}", indexes.len());
+
+        out.push_str("# HELP blackbox_index_documents Documents per index.\n");
+        out.push_str("# TYPE blackbox_index_documents gauge\n");
+        let mut names: Vec<_> = indexes.keys().collect();
+        names.sort();
+        for name in names {
+            let _ = writeln!(
+                out,
+                "blackbox_index_documents{{index=\"{}\"}} {}",
+                escape_label(name),
+                indexes[name].live_count()
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value as required by the text exposition format.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
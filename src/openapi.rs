@@ -0,0 +1,462 @@
+//! The OpenAPI 3 description served at `/openapi.json`. It is written by hand, so a change
+//! to a route, its parameters or its response shape has to be made here as well.
+
+use serde_json::{json, Value};
+
+/// Query parameters shared by `/search` and `/_search`, in the order of `SearchQuery`.
+fn search_parameters() -> Vec<Value> {
+    let query = |name: &str, schema: Value, description: &str| {
+        json!({"name": name, "in": "query", "schema": schema, "description": description})
+    };
+    let string = || json!({"type": "string"});
+    let boolean = || json!({"type": "boolean", "default": false});
+    vec![
+        json!({
+            "name": "q",
+            "in": "query",
+            "required": true,
+            "schema": string(),
+            "description": "Words every hit must contain; empty matches every document."
+        }),
+        query("highlight", boolean(), "Adds a `highlights` object marking matched terms."),
+        query("highlight_tag", json!({"type": "string", "default": "em"}), "Tag wrapped around highlighted terms."),
+        query("fields", string(), "Comma-separated dotted field paths to search."),
+        query("wildcard", boolean(), "Treats each word of `q` as a `*`/`?` pattern."),
+        query("case_sensitive", boolean(), "Matches letter case exactly."),
+        query("fold", boolean(), "Ignores diacritics on both sides."),
+        query("phrase", boolean(), "Requires the words next to each other, in order, within one field."),
+        query("typeahead", boolean(), "Matches the start of tokens with small typos allowed."),
+        query("boosts", string(), "Comma-separated `field:multiplier` pairs."),
+        query("_source", string(), "Comma-separated dotted field paths to return, or `false` for none."),
+        query("limit", json!({"type": "integer", "minimum": 0}), "Most hits to return; capped by `MAX_LIMIT`."),
+        query("explain", boolean(), "Adds an `explanation` object breaking down each hit's score."),
+        query("include_deleted", boolean(), "Also matches soft-deleted documents, marked with `deleted_at`."),
+    ]
+}
+
+fn index_parameter() -> Value {
+    json!({"name": "index", "in": "path", "required": true, "schema": {"type": "string"}})
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({"required": true, "content": {"application/json": {"schema": schema}}})
+}
+
+fn ok(description: &str, schema: Value) -> Value {
+    json!({"description": description, "content": {"application/json": {"schema": schema}}})
+}
+
+fn not_found() -> Value {
+    json!({"$ref": "#/components/responses/NotFound"})
+}
+
+fn bad_request() -> Value {
+    json!({"$ref": "#/components/responses/BadRequest"})
+}
+
+fn total_count_header() -> Value {
+    json!({
+        "description": "Documents that matched before the hits were cut to the limit",
+        "schema": {"type": "integer", "minimum": 0}
+    })
+}
+
+fn schema(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{name}")})
+}
+
+fn object(properties: Value) -> Value {
+    json!({"type": "object", "properties": properties})
+}
+
+pub fn spec() -> Value {
+    let hits = json!({"type": "array", "items": schema("Hit")});
+    let mut scroll_search = search_parameters();
+    scroll_search.insert(0, index_parameter());
+    scroll_search.push(json!({
+        "name": "scroll",
+        "in": "query",
+        "schema": {"type": "integer", "minimum": 0, "maximum": 3600},
+        "description": "Seconds to keep a cursor open; switches the response to a scroll page."
+    }));
+    scroll_search.push(json!({
+        "name": "size",
+        "in": "query",
+        "schema": {"type": "integer", "minimum": 0},
+        "description": "Hits per scroll page, defaulted and capped like `limit`."
+    }));
+    let mut cross_search = search_parameters();
+    cross_search.push(json!({
+        "name": "indexes",
+        "in": "query",
+        "schema": {"type": "string"},
+        "description": "Comma-separated index names; every index when omitted."
+    }));
+    let search_body = {
+        let mut properties = serde_json::Map::new();
+        for parameter in search_parameters() {
+            properties.insert(parameter["name"].as_str().unwrap().to_string(), parameter["schema"].clone());
+        }
+        json!({"type": "object", "required": ["q"], "properties": properties, "additionalProperties": false})
+    };
+    let mut update_body = search_body.clone();
+    update_body["properties"]["doc"] = json!({"type": "object"});
+    update_body["required"] = json!(["q", "doc"]);
+
+    let mut spec = json!({
+        "openapi": "3.0.3",
+        "info": {"title": "BlackBox", "version": env!("CARGO_PKG_VERSION")},
+        "security": [{"bearer": []}, {"apiKey": []}],
+        "paths": {
+            "/": {"get": {
+                "summary": "Greeting; not a stable probe target",
+                "responses": {"200": {
+                    "description": "Hello world",
+                    "content": {"text/plain": {"schema": {"type": "string"}}}
+                }}
+            }},
+            "/health": {"get": {
+                "summary": "Liveness probe",
+                "security": [],
+                "responses": {"200": ok("Serving", object(json!({"status": {"type": "string"}})))}
+            }},
+            "/ready": {"get": {
+                "summary": "Readiness probe",
+                "responses": {
+                    "200": ok("Indexes loaded", object(json!({"status": {"type": "string"}}))),
+                    "503": ok("Still loading", object(json!({"status": {"type": "string"}})))
+                }
+            }},
+            "/metrics": {"get": {
+                "summary": "Prometheus metrics",
+                "responses": {"200": {
+                    "description": "Text exposition format",
+                    "content": {"text/plain": {"schema": {"type": "string"}}}
+                }}
+            }},
+            "/openapi.json": {"get": {
+                "summary": "This document",
+                "responses": {"200": ok("OpenAPI 3 description", json!({"type": "object"}))}
+            }},
+            "/indexes/{index}/documents": {"post": {
+                "summary": "Add a document, creating the index if needed",
+                "parameters": [index_parameter()],
+                "requestBody": json_body(json!({})),
+                "responses": {"200": ok("Assigned id", object(json!({"id": {"type": "integer"}})))}
+            }},
+            "/indexes/{index}/documents/{id}": {"patch": {
+                "summary": "Merge fields into a document; `null` removes a field",
+                "parameters": [
+                    index_parameter(),
+                    {"name": "id", "in": "path", "required": true, "schema": {"type": "integer"}},
+                    {
+                        "name": "version",
+                        "in": "query",
+                        "schema": {"type": "integer"},
+                        "description": "Only update if the document still has this version."
+                    },
+                    {
+                        "name": "If-Match",
+                        "in": "header",
+                        "schema": {"type": "string"},
+                        "description": "Quoted versions, e.g. `\"3\"`, one of which the document must have."
+                    }
+                ],
+                "requestBody": json_body(json!({"type": "object"})),
+                "responses": {
+                    "200": ok("Merged document", schema("Document")),
+                    "404": not_found(),
+                    "409": ok("The document has another version", object(json!({
+                        "error": {"type": "string"},
+                        "version": {"type": "integer"}
+                    }))),
+                    "422": ok("The stored document is not a JSON object", schema("Error"))
+                }
+            }, "delete": {
+                "summary": "Soft-delete a document until the retention period elapses",
+                "parameters": [
+                    index_parameter(),
+                    {"name": "id", "in": "path", "required": true, "schema": {"type": "integer"}}
+                ],
+                "responses": {
+                    "200": ok("Tombstone", object(json!({
+                        "id": {"type": "integer"},
+                        "deleted_at": {"type": "integer"}
+                    }))),
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/documents/{id}/_undelete": {"post": {
+                "summary": "Restore a soft-deleted document",
+                "parameters": [
+                    index_parameter(),
+                    {"name": "id", "in": "path", "required": true, "schema": {"type": "integer"}}
+                ],
+                "responses": {"200": ok("Restored document", schema("Document")), "404": not_found()}
+            }},
+            "/indexes/{index}/_bulk": {"post": {
+                "summary": "Elasticsearch-style bulk index and delete",
+                "parameters": [index_parameter()],
+                "requestBody": {
+                    "required": true,
+                    "content": {"application/x-ndjson": {"schema": {"type": "string"}}}
+                },
+                "responses": {
+                    "200": ok("One item per action", object(json!({
+                        "took": {"type": "integer"},
+                        "errors": {"type": "boolean"},
+                        "items": {"type": "array", "items": {"type": "object"}}
+                    }))),
+                    "400": bad_request()
+                }
+            }},
+            "/indexes/{index}/_import_csv": {"post": {
+                "summary": "Import CSV rows as documents",
+                "parameters": [index_parameter()],
+                "requestBody": {"required": true, "content": {"text/csv": {"schema": {"type": "string"}}}},
+                "responses": {"200": ok("Row counts", object(json!({
+                    "imported": {"type": "integer"},
+                    "failed": {"type": "integer"}
+                })))}
+            }},
+            "/indexes/{index}/_export": {"get": {
+                "summary": "Every document of an index",
+                "parameters": [
+                    index_parameter(),
+                    {"name": "format", "in": "query", "schema": {"type": "string", "enum": ["json", "csv"], "default": "json"}}
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The documents",
+                        "content": {
+                            "application/json": {"schema": {"type": "array", "items": schema("Document")}},
+                            "text/csv": {"schema": {"type": "string"}}
+                        }
+                    },
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/search": {"get": {
+                "summary": "Full-text search ranked by BM25",
+                "parameters": scroll_search,
+                "responses": {
+                    "200": {
+                        "description": "Hits, or a scroll page when `scroll` is given",
+                        "headers": {"X-Total-Count": total_count_header()},
+                        "content": {
+                            "application/json": {"schema": {"oneOf": [hits.clone(), schema("ScrollPage")]}},
+                            "application/x-ndjson": {"schema": {"type": "string"}}
+                        }
+                    },
+                    "404": not_found(),
+                    "429": ok("Rate limit exceeded, or too many scroll cursors are open", schema("Error"))
+                }
+            }},
+            "/indexes/{index}/search_geo": {"get": {
+                "summary": "Documents nearest to a point",
+                "parameters": [
+                    index_parameter(),
+                    {"name": "lat", "in": "query", "required": true, "schema": {"type": "number"}},
+                    {"name": "lon", "in": "query", "required": true, "schema": {"type": "number"}},
+                    {"name": "field", "in": "query", "schema": {"type": "string"}},
+                    {"name": "radius_km", "in": "query", "schema": {"type": "number"}},
+                    {"name": "limit", "in": "query", "schema": {"type": "integer", "minimum": 0}}
+                ],
+                "responses": {"200": ok("Hits scored by distance in km", hits.clone()), "404": not_found()}
+            }},
+            "/_scroll": {"get": {
+                "summary": "Next page of a scroll cursor",
+                "parameters": [{"name": "scroll_id", "in": "query", "required": true, "schema": {"type": "string"}}],
+                "responses": {"200": ok("The page", schema("ScrollPage")), "404": not_found()}
+            }},
+            "/_search": {"get": {
+                "summary": "Search several indexes and merge by score",
+                "parameters": cross_search,
+                "responses": {
+                    "200": {
+                        "description": "Hits tagged with `index`",
+                        "headers": {"X-Total-Count": total_count_header()},
+                        "content": {"application/json": {"schema": hits.clone()}}
+                    },
+                    "404": not_found()
+                }
+            }},
+            "/_msearch": {"post": {
+                "summary": "Several searches in one request",
+                "requestBody": json_body(json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["index", "query"],
+                        "properties": {"index": {"type": "string"}, "query": search_body.clone()},
+                        "additionalProperties": false
+                    }
+                })),
+                "responses": {
+                    "200": ok("One entry per search", json!({
+                        "type": "array",
+                        "items": {"oneOf": [hits, schema("Error")]}
+                    })),
+                    "400": bad_request()
+                }
+            }},
+            "/indexes/{index}/_delete_by_query": {"post": {
+                "summary": "Delete every document a search matches",
+                "parameters": [index_parameter()],
+                "requestBody": json_body(search_body.clone()),
+                "responses": {
+                    "200": ok("Deleted count", object(json!({"deleted": {"type": "integer"}}))),
+                    "400": bad_request(),
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/_update_by_query": {"post": {
+                "summary": "Merge `doc` into every document a search matches",
+                "parameters": [index_parameter()],
+                "requestBody": json_body(update_body),
+                "responses": {
+                    "200": ok("Updated count", object(json!({"updated": {"type": "integer"}}))),
+                    "400": bad_request(),
+                    "404": not_found()
+                }
+            }},
+            "/_reindex": {"post": {
+                "summary": "Copy matching documents into another index",
+                "requestBody": json_body(json!({
+                    "type": "object",
+                    "required": ["source", "dest"],
+                    "properties": {
+                        "source": {"type": "string"},
+                        "dest": {"type": "string"},
+                        "query": search_body
+                    },
+                    "additionalProperties": false
+                })),
+                "responses": {
+                    "200": ok("Copied count", object(json!({"reindexed": {"type": "integer"}}))),
+                    "400": bad_request(),
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/_stats": {"get": {
+                "summary": "Index counts",
+                "parameters": [index_parameter()],
+                "responses": {
+                    "200": ok("Counts", object(json!({
+                        "documents": {"type": "integer"},
+                        "deleted": {"type": "integer"},
+                        "next_id": {"type": "integer"},
+                        "terms": {"type": "integer"},
+                        "tokens": {"type": "integer"},
+                        "pending_log_records": {"type": "integer"},
+                        "approximate_size_bytes": {"type": "integer"}
+                    }))),
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/_snapshot": {"get": {
+                "summary": "The index as a bincode snapshot",
+                "parameters": [index_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "Snapshot bytes",
+                        "content": {"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}}
+                    },
+                    "404": not_found()
+                }
+            }},
+            "/indexes/{index}/_restore": {"post": {
+                "summary": "Replace an index with a snapshot",
+                "parameters": [index_parameter()],
+                "requestBody": {
+                    "required": true,
+                    "content": {"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}}
+                },
+                "responses": {
+                    "200": ok("Restored count", object(json!({"restored": {"type": "integer"}}))),
+                    "400": bad_request()
+                }
+            }}
+        },
+        "components": {
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+                "apiKey": {"type": "apiKey", "in": "header", "name": "X-API-Key"}
+            },
+            "schemas": {
+                "Document": object(json!({
+                    "id": {"type": "integer"},
+                    "version": {"type": "integer"},
+                    "document": {}
+                })),
+                "Hit": object(json!({
+                    "id": {"type": "integer"},
+                    "version": {"type": "integer"},
+                    "score": {"type": "number"},
+                    "document": {},
+                    "highlights": {
+                        "type": "object",
+                        "additionalProperties": {"type": "array", "items": {"type": "string"}}
+                    },
+                    "index": {"type": "string"},
+                    "explanation": {"type": "object"},
+                    "deleted_at": {"type": "integer"}
+                })),
+                "ScrollPage": object(json!({
+                    "scroll_id": {"type": "string", "nullable": true},
+                    "hits": {"type": "array", "items": schema("Hit")}
+                })),
+                "Error": object(json!({"error": {"type": "string"}}))
+            },
+            "responses": {
+                "BadRequest": ok("Malformed or unknown parameters or body", schema("Error")),
+                "NotFound": ok("Index, document or scroll not found", schema("Error")),
+                "TooLarge": ok("Body over the size limit", schema("Error")),
+                "UnsupportedEncoding": ok("`Content-Encoding` other than gzip or deflate", schema("Error")),
+                "Unauthorized": ok("Missing or invalid API key, when `API_KEY` is set", schema("Error")),
+                "RateLimited": {
+                    "description": "Rate limit exceeded, when `RATE_LIMIT_RPS` is set",
+                    "headers": {"Retry-After": {"schema": {"type": "integer"}}},
+                    "content": {"application/json": {"schema": schema("Error")}}
+                }
+            }
+        }
+    });
+    add_shared_responses(&mut spec);
+    spec
+}
+
+/// Adds the responses that come from the filters in front of the handlers rather than from
+/// the handlers themselves: the API key and rate limit checks on every route but `/health`,
+/// query string parsing, and reading the body.
+fn add_shared_responses(spec: &mut Value) {
+    let reference = |name: &str| json!({"$ref": format!("#/components/responses/{name}")});
+    let Some(paths) = spec["paths"].as_object_mut() else {
+        return;
+    };
+    for (path, operations) in paths {
+        let Some(operations) = operations.as_object_mut() else {
+            continue;
+        };
+        for operation in operations.values_mut() {
+            let has_query = operation["parameters"]
+                .as_array()
+                .is_some_and(|parameters| parameters.iter().any(|p| p["in"] == "query"));
+            let has_body = operation.get("requestBody").is_some();
+            let Some(responses) = operation["responses"].as_object_mut() else {
+                continue;
+            };
+            if has_query || has_body {
+                responses.entry("400").or_insert_with(|| reference("BadRequest"));
+            }
+            if has_body {
+                responses.entry("413").or_insert_with(|| reference("TooLarge"));
+                responses.entry("415").or_insert_with(|| reference("UnsupportedEncoding"));
+            }
+            if path != "/health" {
+                responses.entry("401").or_insert_with(|| reference("Unauthorized"));
+                responses.entry("429").or_insert_with(|| reference("RateLimited"));
+            }
+        }
+    }
+}
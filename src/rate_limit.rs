@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::filters::BoxedFilter;
+use warp::{Filter, Rejection};
+
+/// Rejection for clients that have used up their request budget.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Once this many clients are tracked, buckets that have refilled completely are dropped.
+const PRUNE_AT: usize = 4096;
+
+/// Per-IP token buckets refilling at `rate` tokens per second up to `burst`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one token for `ip`, or returns how long until the next one is available.
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_AT {
+            let full_after = self.burst / self.rate;
+            buckets.retain(|_, b| now.duration_since(b.updated).as_secs_f64() < full_after);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+/// Rejects requests with [`RateLimited`] once their client IP runs out of tokens.
+/// Without a limiter every request passes.
+pub fn rate_limit(limiter: Option<Arc<RateLimiter>>) -> BoxedFilter<()> {
+    warp::addr::remote()
+        .and_then(move |addr: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                match (limiter, addr) {
+                    (Some(limiter), Some(addr)) => limiter
+                        .acquire(addr.ip())
+                        .map_err(|retry_after| warp::reject::custom(RateLimited { retry_after })),
+                    _ => Ok::<_, Rejection>(()),
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
@@ -0,0 +1,51 @@
+//! `X-Request-Id` correlation: every response carries the id the client sent, or a fresh one,
+//! and the access log line for the request records the same id.
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use warp::filters::BoxedFilter;
+use warp::http::HeaderValue;
+use warp::reply::Response;
+use warp::Filter;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longest client-supplied id that is passed through; longer ones are replaced.
+const MAX_LEN: usize = 128;
+
+thread_local! {
+    /// Id of the response just produced on this thread. warp calls the log hook in the same
+    /// poll that finishes the response, so the hook picks it up before anything else runs.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Wraps `app` so each response gets an `X-Request-Id` header.
+pub fn with_request_id(app: BoxedFilter<(Response,)>) -> BoxedFilter<(Response,)> {
+    warp::header::optional::<String>(HEADER)
+        .map(|id: Option<String>| {
+            id.filter(|id| !id.is_empty() && id.len() <= MAX_LEN)
+                .unwrap_or_else(generate)
+        })
+        .and(app)
+        .map(|id: String, mut response: Response| {
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response.headers_mut().insert(HEADER, value);
+            }
+            CURRENT.with(|current| *current.borrow_mut() = Some(id));
+            response
+        })
+        .boxed()
+}
+
+/// The id of the response finished last on this thread.
+pub fn take() -> Option<String> {
+    CURRENT.with(|current| current.borrow_mut().take())
+}
+
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}", RandomState::new().hash_one(n))
+}
@@ -0,0 +1,112 @@
+//! Server-held cursors for paging through large result sets. A cursor keeps the ranked ids
+//! of a search rather than its hits, so each page is rendered from the documents as they are
+//! when it is fetched; documents deleted in between are skipped.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::SearchQuery;
+
+/// Longest a cursor may be kept alive between requests.
+pub const MAX_KEEP_ALIVE: Duration = Duration::from_secs(3600);
+
+struct Cursor {
+    index: String,
+    query: Arc<SearchQuery>,
+    /// (document id, score) of the hits not returned yet, in rank order.
+    remaining: VecDeque<(usize, f64)>,
+    size: usize,
+    keep_alive: Duration,
+    expires: Instant,
+}
+
+/// One page taken from a cursor.
+pub struct Page {
+    pub index: String,
+    pub query: Arc<SearchQuery>,
+    pub hits: Vec<(usize, f64)>,
+    /// Whether the cursor is still open after this page.
+    pub more: bool,
+}
+
+pub struct Scrolls {
+    cursors: Mutex<HashMap<String, Cursor>>,
+    /// Most cursors open at once; further searches with `scroll` are refused until some
+    /// finish or expire.
+    max_open: usize,
+    counter: AtomicU64,
+    /// Seeds scroll ids so they cannot be guessed from one another.
+    hasher: RandomState,
+}
+
+impl Scrolls {
+    pub fn new(max_open: usize) -> Self {
+        Scrolls {
+            cursors: Mutex::default(),
+            max_open,
+            counter: AtomicU64::new(0),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Opens a cursor over `hits` and returns its id with the first page, or `None` when
+    /// `max_open` cursors are already open.
+    pub fn open(
+        &self,
+        index: String,
+        query: SearchQuery,
+        hits: Vec<(usize, f64)>,
+        size: usize,
+        keep_alive: Duration,
+    ) -> Option<(String, Page)> {
+        let mut cursors = self.cursors.lock().unwrap();
+        let now = Instant::now();
+        cursors.retain(|_, c| c.expires > now);
+        if cursors.len() >= self.max_open {
+            return None;
+        }
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{:016x}{n:x}", self.hasher.hash_one(n));
+        let keep_alive = keep_alive.min(MAX_KEEP_ALIVE);
+        let cursor = Cursor {
+            index,
+            query: Arc::new(query),
+            remaining: hits.into(),
+            size: size.max(1),
+            keep_alive,
+            expires: now + keep_alive,
+        };
+        cursors.insert(id.clone(), cursor);
+        let page = Self::take(&mut cursors, &id).unwrap();
+        Some((id, page))
+    }
+
+    /// The next page of cursor `id`, extending its life, or `None` if it is unknown or has
+    /// expired.
+    pub fn next(&self, id: &str) -> Option<Page> {
+        Self::take(&mut self.cursors.lock().unwrap(), id)
+    }
+
+    fn take(cursors: &mut HashMap<String, Cursor>, id: &str) -> Option<Page> {
+        let now = Instant::now();
+        cursors.retain(|_, c| c.expires > now);
+        let cursor = cursors.get_mut(id)?;
+        let size = cursor.size.min(cursor.remaining.len());
+        let hits: Vec<_> = cursor.remaining.drain(..size).collect();
+        cursor.expires = now + cursor.keep_alive;
+        let page = Page {
+            index: cursor.index.clone(),
+            query: cursor.query.clone(),
+            hits,
+            more: !cursor.remaining.is_empty(),
+        };
+        if !page.more {
+            cursors.remove(id);
+        }
+        Some(page)
+    }
+}
@@ -0,0 +1,763 @@
+//! On-disk layout: each index is a snapshot, `{name}.bin` or `{name}.json` depending on the
+//! storage format, plus a `{name}.wal` log of the changes made since that snapshot was
+//! written. Loading replays the log over the snapshot; compaction sets the log aside as
+//! `{name}.wal.old`, folds it into a fresh snapshot and removes it.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+
+use crate::{Config, Document, Index, Indexes};
+
+#[derive(Serialize, Deserialize)]
+pub struct PersistedDocument {
+    id: usize,
+    data: Vec<u8>, // JSON-encoded
+}
+
+impl PersistedDocument {
+    fn encode(doc: &Document) -> Option<Self> {
+        serde_json::to_vec(&doc.data)
+            .ok()
+            .map(|data| PersistedDocument { id: doc.id, data })
+    }
+
+    /// The document at its first version; later versions are restored by the caller.
+    fn decode(self) -> Option<Document> {
+        serde_json::from_slice(&self.data)
+            .ok()
+            .map(|data| Document { id: self.id, version: 1, data })
+    }
+}
+
+/// A document as written to JSON snapshots, readable with ordinary tools.
+#[derive(Serialize, Deserialize)]
+struct JsonDocument {
+    id: usize,
+    version: u64,
+    /// Set on soft-deleted documents, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<u64>,
+    data: Value,
+}
+
+/// Layout of JSON snapshot files.
+#[derive(Serialize, Deserialize)]
+struct JsonSnapshot {
+    next_id: usize,
+    docs: Vec<JsonDocument>,
+}
+
+/// Starts bincode snapshots that carry a header; older ones are a bare document list.
+const SNAPSHOT_MAGIC: [u8; 4] = *b"BBS3";
+
+/// Each document with its version and, if soft-deleted, its deletion time.
+#[derive(Serialize, Deserialize)]
+struct BincodeSnapshot {
+    next_id: usize,
+    docs: Vec<(PersistedDocument, u64, Option<u64>)>,
+}
+
+/// The contents of a snapshot: the documents plus the id the next insert will get, which
+/// can be past the newest document once documents have been deleted, and the soft-deleted
+/// ids with their deletion times.
+pub struct Snapshot {
+    pub next_id: usize,
+    pub docs: Vec<Document>,
+    pub tombstones: HashMap<usize, u64>,
+}
+
+impl Snapshot {
+    /// Snapshots from before the counter was stored continue after their newest document.
+    fn legacy(docs: Vec<Document>) -> Self {
+        let next_id = docs.iter().map(|d| d.id + 1).max().unwrap_or(1);
+        Snapshot { next_id, docs, tombstones: HashMap::new() }
+    }
+
+    /// Puts the documents in ascending id order, which lookups by id rely on, and refuses a
+    /// snapshot naming the same id twice.
+    fn checked(mut self) -> Result<Self, std::io::Error> {
+        self.docs.sort_by_key(|d| d.id);
+        if let Some(pair) = self.docs.windows(2).find(|pair| pair[0].id == pair[1].id) {
+            return Err(std::io::Error::other(format!("duplicate document id {}", pair[0].id)));
+        }
+        Ok(self)
+    }
+}
+
+/// A single change appended to an index's write-ahead log.
+#[derive(Serialize, Deserialize)]
+pub enum WalRecord {
+    Insert(PersistedDocument),
+    /// Soft-deletes a document at the given time, in milliseconds since the Unix epoch.
+    Tombstone(usize, u64),
+    /// Clears a document's tombstone.
+    Undelete(usize),
+    /// New data for an existing document together with the version it now has.
+    Revise(PersistedDocument, u64),
+}
+
+impl WalRecord {
+    pub fn insert(doc: &Document) -> Result<Self, std::io::Error> {
+        PersistedDocument::encode(doc)
+            .map(WalRecord::Insert)
+            .ok_or_else(|| std::io::Error::other("document is not serializable"))
+    }
+
+    pub fn update(doc: &Document) -> Result<Self, std::io::Error> {
+        PersistedDocument::encode(doc)
+            .map(|raw| WalRecord::Revise(raw, doc.version))
+            .ok_or_else(|| std::io::Error::other("document is not serializable"))
+    }
+}
+
+/// Encoding of snapshot files. The write-ahead log is always bincode.
+#[derive(Clone, Copy, PartialEq)]
+pub enum StorageFormat {
+    Bincode,
+    Json,
+}
+
+impl StorageFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bincode" => Some(StorageFormat::Bincode),
+            "json" => Some(StorageFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            StorageFormat::Bincode => "bin",
+            StorageFormat::Json => "json",
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            StorageFormat::Bincode => StorageFormat::Json,
+            StorageFormat::Json => StorageFormat::Bincode,
+        }
+    }
+
+    fn encode(
+        self,
+        docs: &[Document],
+        tombstones: &HashMap<usize, u64>,
+        next_id: usize,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        match self {
+            StorageFormat::Bincode => encode_snapshot(docs, tombstones, next_id),
+            StorageFormat::Json => {
+                let docs = docs
+                    .iter()
+                    .map(|d| JsonDocument {
+                        id: d.id,
+                        version: d.version,
+                        deleted_at: tombstones.get(&d.id).copied(),
+                        data: d.data.clone(),
+                    })
+                    .collect();
+                let raw = JsonSnapshot { next_id, docs };
+                serde_json::to_vec_pretty(&raw).map_err(std::io::Error::other)
+            }
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<Snapshot, std::io::Error> {
+        match self {
+            StorageFormat::Bincode => decode_snapshot(bytes),
+            StorageFormat::Json => {
+                let raw: JsonSnapshot = serde_json::from_slice(bytes).map_err(std::io::Error::other)?;
+                let tombstones = raw.docs.iter().filter_map(|d| Some((d.id, d.deleted_at?))).collect();
+                let docs = raw
+                    .docs
+                    .into_iter()
+                    .map(|d| Document {
+                        id: d.id,
+                        version: d.version,
+                        data: d.data,
+                    })
+                    .collect();
+                let snapshot = Snapshot { next_id: raw.next_id, docs, tombstones };
+                // JSON snapshots may have been edited by hand.
+                snapshot.checked()
+            }
+        }
+    }
+}
+
+/// Frame header that starts every zstd-compressed snapshot.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompresses `content` if it is zstd-compressed; otherwise returns it unchanged.
+fn decompress(content: Vec<u8>) -> Result<Vec<u8>, std::io::Error> {
+    if content.starts_with(&ZSTD_MAGIC) {
+        zstd::decode_all(content.as_slice())
+    } else {
+        Ok(content)
+    }
+}
+
+fn snapshot_path(data_dir: &Path, name: &str, format: StorageFormat) -> PathBuf {
+    data_dir.join(format!("{name}.{}", format.extension()))
+}
+
+fn wal_path(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(format!("{name}.wal"))
+}
+
+/// Suffix of a log set aside by a compaction that is writing the snapshot covering it.
+const ROTATED_WAL_SUFFIX: &str = ".wal.old";
+
+fn rotated_wal_path(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join(format!("{name}{ROTATED_WAL_SUFFIX}"))
+}
+
+/// Serializes compactions with each other and with restores, which write the same files.
+static COMPACTION: Mutex<()> = Mutex::const_new(());
+
+/// Held by a restore while it replaces an index, so a compaction started earlier cannot
+/// rename an older snapshot over the restored one. Take it before the index lock.
+pub async fn lock_compactions() -> MutexGuard<'static, ()> {
+    COMPACTION.lock().await
+}
+
+pub async fn load_indexes(config: &Config) -> Indexes {
+    let data_dir = config.data_dir.as_path();
+    let mut map = HashMap::new();
+    if let Err(e) = fs::create_dir_all(data_dir).await {
+        eprintln!("failed to create data dir: {e}");
+        return Arc::new(RwLock::new(map));
+    }
+
+    let mut entries = match fs::read_dir(data_dir).await {
+        Ok(e) => e,
+        Err(_) => return Arc::new(RwLock::new(map)),
+    };
+
+    let mut names = BTreeSet::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if matches!(path.extension().and_then(|s| s.to_str()), Some("bin" | "json" | "wal"))
+            && let Some(name) = path.file_stem().and_then(|s| s.to_str())
+        {
+            names.insert(name.to_string());
+        } else if let Some(name) = path
+            .file_name()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.strip_suffix(ROTATED_WAL_SUFFIX))
+        {
+            names.insert(name.to_string());
+        }
+    }
+
+    for name in names {
+        let mut docs = Vec::new();
+        let mut tombstones = HashMap::new();
+        let mut next_id = 1;
+        // Prefer the configured format, but still pick up snapshots written in the other one.
+        for format in [config.storage_format, config.storage_format.other()] {
+            if let Ok(content) = fs::read(snapshot_path(data_dir, &name, format)).await {
+                match decompress(content).and_then(|bytes| format.decode(&bytes)) {
+                    Ok(snapshot) => {
+                        (docs, tombstones, next_id) = (snapshot.docs, snapshot.tombstones, snapshot.next_id)
+                    }
+                    // Starting with the index empty would overwrite the snapshot at the next
+                    // compaction, so leave the file for someone to fix.
+                    Err(e) => {
+                        eprintln!("failed to read snapshot of index {name}: {e}");
+                        std::process::exit(1);
+                    }
+                }
+                break;
+            }
+        }
+        let mut wal_records = 0;
+        // A log set aside by an interrupted compaction holds the older records.
+        for wal in [rotated_wal_path(data_dir, &name), wal_path(data_dir, &name)] {
+            let Ok(content) = fs::read(&wal).await else {
+                continue;
+            };
+            let (valid, records) = replay_wal(&mut docs, &mut tombstones, &mut next_id, &content);
+            wal_records += records;
+            if valid < content.len() {
+                eprintln!("discarding torn tail of log for index {name}");
+                let truncated = match fs::OpenOptions::new().write(true).open(&wal).await {
+                    Ok(file) => file.set_len(valid as u64).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = truncated {
+                    eprintln!("failed to truncate log of index {name}: {e}");
+                }
+            }
+        }
+        // A log left by an interrupted compaction can re-insert purged documents behind
+        // newer ones.
+        docs.sort_by_key(|d| d.id);
+        let mut index = Index::new(config.analyzer.clone());
+        docs.into_iter().for_each(|doc| index.insert(doc));
+        index.next_id = index.next_id.max(next_id);
+        index.wal_records = wal_records;
+        index.tombstones = tombstones;
+        map.insert(name, index);
+    }
+
+    Arc::new(RwLock::new(map))
+}
+
+/// Applies the records of a log to `docs`. Returns how many leading bytes held whole
+/// records (anything after that is a torn write from a crash mid-append) and how many
+/// records were applied. Inserts of ids already present are skipped, so a log left behind
+/// by an interrupted compaction replays safely. `next_id` is moved past every inserted id,
+/// including those of documents deleted later in the log. Soft deletes are collected in
+/// `tombstones`, keyed by id, for documents that are still in `docs`.
+fn replay_wal(
+    docs: &mut Vec<Document>,
+    tombstones: &mut HashMap<usize, u64>,
+    next_id: &mut usize,
+    content: &[u8],
+) -> (usize, usize) {
+    let mut seen: HashSet<usize> = docs.iter().map(|d| d.id).collect();
+    let mut offset = 0;
+    let mut records = 0;
+    while let Some(header) = content.get(offset..offset + 4) {
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let Some(record) = content.get(offset + 4..offset + 4 + len) else {
+            break;
+        };
+        match bincode::deserialize::<WalRecord>(record) {
+            Ok(WalRecord::Insert(raw)) => {
+                *next_id = (*next_id).max(raw.id + 1);
+                if seen.insert(raw.id)
+                    && let Some(doc) = raw.decode()
+                {
+                    docs.push(doc);
+                }
+            }
+            Ok(WalRecord::Revise(raw, version)) => {
+                if seen.contains(&raw.id)
+                    && let Some(doc) = raw.decode()
+                    && let Some(slot) = docs.iter_mut().find(|d| d.id == doc.id)
+                {
+                    slot.version = version;
+                    slot.data = doc.data;
+                }
+            }
+            Ok(WalRecord::Tombstone(id, at)) => {
+                if seen.contains(&id) {
+                    tombstones.insert(id, at);
+                }
+            }
+            Ok(WalRecord::Undelete(id)) => {
+                tombstones.remove(&id);
+            }
+            Err(_) => break,
+        }
+        offset += 4 + len;
+        records += 1;
+    }
+    (offset, records)
+}
+
+/// Durably appends `records` to the index's log with a single sync. A failed append is cut
+/// back off the log, so records appended after it are not lost behind a torn one.
+pub async fn append_log(config: &Config, name: &str, records: &[WalRecord]) -> Result<(), std::io::Error> {
+    let mut buf = Vec::new();
+    for record in records {
+        let bytes = bincode::serialize(record).map_err(std::io::Error::other)?;
+        buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&bytes);
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(wal_path(&config.data_dir, name))
+        .await?;
+    let len = file.metadata().await?.len();
+    let appended = async {
+        file.write_all(&buf).await?;
+        // tokio finishes writes in the background and only reports their errors on flush.
+        file.flush().await?;
+        file.sync_data().await?;
+        // A log created by this append only survives a crash once its directory entry does.
+        if len == 0 {
+            sync_dir(&config.data_dir).await?;
+        }
+        Ok(())
+    };
+    if let Err(e) = appended.await {
+        if let Err(e) = file.set_len(len).await {
+            eprintln!("failed to cut a failed append off the log of index {name}: {e}");
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Compacts, every `flush_interval`, the indexes whose log has reached `wal_compact_every`
+/// records. Keeps snapshot rewrites off the insert path and bounds how often they happen.
+pub async fn flush_loop(indexes: Indexes, config: Arc<Config>) {
+    let mut tick = tokio::time::interval(config.flush_interval);
+    loop {
+        tick.tick().await;
+        flush_indexes(&indexes, &config, config.wal_compact_every.max(1)).await;
+    }
+}
+
+/// Compacts every index with at least `min_records` log records since its last snapshot.
+/// Soft-deleted documents are kept in the snapshot with their tombstones, except those
+/// older than `tombstone_retention`, which are purged for good first.
+///
+/// Each snapshot is encoded under a read lock, which holds off appends so that the log can
+/// be set aside in step with it, and is then written and synced with no lock held.
+pub async fn flush_indexes(indexes: &Indexes, config: &Config, min_records: usize) {
+    let _compacting = COMPACTION.lock().await;
+    let due: Vec<String> = {
+        let map = indexes.read().await;
+        map.iter()
+            .filter(|(_, index)| index.wal_records >= min_records)
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    if due.is_empty() {
+        return;
+    }
+    if let Some(retention) = config.tombstone_retention {
+        let cutoff = crate::now_millis().saturating_sub(retention.as_millis() as u64);
+        let mut map = indexes.write().await;
+        for name in &due {
+            if let Some(index) = map.get_mut(name) {
+                index.purge_deleted_before(cutoff);
+            }
+        }
+    }
+    for name in due {
+        let encoded = {
+            let map = indexes.read().await;
+            let Some(index) = map.get(&name) else {
+                continue;
+            };
+            let bytes = config.storage_format.encode(&index.docs, &index.tombstones, index.next_id());
+            match bytes {
+                Ok(bytes) => rotate_log(&config.data_dir, &name).await.map(|()| (bytes, index.wal_records)),
+                Err(e) => Err(e),
+            }
+        };
+        // The logs still hold every change, so a failed compaction only delays the next one.
+        let result = match encoded {
+            Ok((bytes, records)) => {
+                let data_dir = config.data_dir.as_path();
+                let written = async {
+                    persist_index(data_dir, &name, bytes, config.storage_format, config.compress).await?;
+                    remove_if_exists(&rotated_wal_path(data_dir, &name)).await?;
+                    remove_if_exists(&snapshot_path(data_dir, &name, config.storage_format.other())).await
+                };
+                written.await.map(|()| records)
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(records) => {
+                if let Some(index) = indexes.write().await.get_mut(&name) {
+                    index.wal_records = index.wal_records.saturating_sub(records);
+                }
+            }
+            Err(e) => eprintln!("failed to compact index {name}: {e}"),
+        }
+    }
+}
+
+/// Sets the log of `name` aside so that appends start a fresh one. A log already set aside
+/// by a failed compaction is kept and the current one is added to its end.
+async fn rotate_log(data_dir: &Path, name: &str) -> Result<(), std::io::Error> {
+    let wal = wal_path(data_dir, name);
+    let rotated = rotated_wal_path(data_dir, name);
+    if fs::try_exists(&rotated).await? {
+        let content = match fs::read(&wal).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut file = fs::OpenOptions::new().append(true).open(&rotated).await?;
+        file.write_all(&content).await?;
+        file.flush().await?;
+        file.sync_data().await?;
+        return remove_if_exists(&wal).await;
+    }
+    match fs::rename(&wal, &rotated).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        Ok(()) => sync_dir(data_dir).await,
+    }
+}
+
+/// Serializes `docs`, their tombstones and the id counter in the bincode snapshot format.
+pub fn encode_snapshot(
+    docs: &[Document],
+    tombstones: &HashMap<usize, u64>,
+    next_id: usize,
+) -> Result<Vec<u8>, std::io::Error> {
+    let docs = docs
+        .iter()
+        .filter_map(|d| Some((PersistedDocument::encode(d)?, d.version, tombstones.get(&d.id).copied())))
+        .collect();
+    let mut bytes = SNAPSHOT_MAGIC.to_vec();
+    bincode::serialize_into(&mut bytes, &BincodeSnapshot { next_id, docs })
+        .map_err(std::io::Error::other)?;
+    Ok(bytes)
+}
+
+/// Parses bytes produced by [`encode_snapshot`], or by versions that wrote no header.
+pub fn decode_snapshot(bytes: &[u8]) -> Result<Snapshot, std::io::Error> {
+    decode_bincode(bytes)?.checked()
+}
+
+fn decode_bincode(bytes: &[u8]) -> Result<Snapshot, std::io::Error> {
+    let Some(rest) = bytes.strip_prefix(&SNAPSHOT_MAGIC) else {
+        let raw: Vec<PersistedDocument> = bincode::deserialize(bytes).map_err(std::io::Error::other)?;
+        return Ok(Snapshot::legacy(raw.into_iter().filter_map(PersistedDocument::decode).collect()));
+    };
+    let raw: BincodeSnapshot = bincode::deserialize(rest).map_err(std::io::Error::other)?;
+    let tombstones = raw.docs.iter().filter_map(|(doc, _, at)| Some((doc.id, (*at)?))).collect();
+    let docs = raw
+        .docs
+        .into_iter()
+        .filter_map(|(doc, version, _)| Some(Document { version, ..doc.decode()? }))
+        .collect();
+    Ok(Snapshot { next_id: raw.next_id, docs, tombstones })
+}
+
+/// Writes a fresh snapshot of `docs` and drops the files it supersedes: the logs and any
+/// snapshot left in the other storage format. Callers hold the index's write lock and
+/// [`lock_compactions`].
+pub async fn compact_index(
+    config: &Config,
+    name: &str,
+    docs: &[Document],
+    tombstones: &HashMap<usize, u64>,
+    next_id: usize,
+) -> Result<(), std::io::Error> {
+    let data_dir = config.data_dir.as_path();
+    let format = config.storage_format;
+    let bytes = format.encode(docs, tombstones, next_id)?;
+    persist_index(data_dir, name, bytes, format, config.compress).await?;
+    remove_if_exists(&wal_path(data_dir, name)).await?;
+    remove_if_exists(&rotated_wal_path(data_dir, name)).await?;
+    remove_if_exists(&snapshot_path(data_dir, name, format.other())).await
+}
+
+async fn remove_if_exists(path: &Path) -> Result<(), std::io::Error> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces the snapshot of `name` with `bytes`, encoded in `format`.
+async fn persist_index(
+    data_dir: &Path,
+    name: &str,
+    mut bytes: Vec<u8>,
+    format: StorageFormat,
+    compress: bool,
+) -> Result<(), std::io::Error> {
+    let path = snapshot_path(data_dir, name, format);
+    if compress {
+        bytes = zstd::encode_all(bytes.as_slice(), 0)?;
+    }
+
+    // Write next to the target and rename over it so a crash never leaves a truncated index.
+    let tmp = path.with_extension(format!("{}.tmp", format.extension()));
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(&bytes).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, &path).await?;
+    // The rename only survives a crash once the directory is synced, and the logs it
+    // supersedes are removed right after.
+    sync_dir(data_dir).await
+}
+
+async fn sync_dir(dir: &Path) -> Result<(), std::io::Error> {
+    fs::File::open(dir).await?.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc(id: usize, version: u64, text: &str) -> Document {
+        Document { id, version, data: json!({ "t": text }) }
+    }
+
+    fn record(record: &WalRecord) -> Vec<u8> {
+        let bytes = bincode::serialize(record).unwrap();
+        let mut framed = (bytes.len() as u32).to_le_bytes().to_vec();
+        framed.extend_from_slice(&bytes);
+        framed
+    }
+
+    fn config(test: &str, format: StorageFormat, compress: bool) -> Config {
+        let dir = std::env::temp_dir().join(format!("blackbox-{test}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::from_env();
+        config.data_dir = dir;
+        config.storage_format = format;
+        config.compress = compress;
+        config
+    }
+
+    fn contents(docs: &[Document]) -> Vec<(usize, u64, Value)> {
+        docs.iter().map(|d| (d.id, d.version, d.data.clone())).collect()
+    }
+
+    #[test]
+    fn replay_applies_records_in_log_order() {
+        let mut log = Vec::new();
+        for r in [
+            WalRecord::insert(&doc(1, 1, "one")).unwrap(),
+            WalRecord::insert(&doc(2, 1, "two")).unwrap(),
+            WalRecord::update(&doc(1, 2, "one again")).unwrap(),
+            WalRecord::Tombstone(2, 50),
+            WalRecord::Undelete(2),
+            WalRecord::Tombstone(1, 60),
+            // Left behind by an interrupted compaction: already present, so skipped.
+            WalRecord::insert(&doc(2, 1, "stale")).unwrap(),
+            WalRecord::insert(&doc(5, 1, "five")).unwrap(),
+            // Ids that are not in the index are ignored.
+            WalRecord::Tombstone(9, 70),
+        ] {
+            log.extend(record(&r));
+        }
+        let (mut docs, mut tombstones, mut next_id) = (Vec::new(), HashMap::new(), 1);
+        let (valid, records) = replay_wal(&mut docs, &mut tombstones, &mut next_id, &log);
+        assert_eq!((valid, records), (log.len(), 9));
+        assert_eq!(
+            contents(&docs),
+            vec![
+                (1, 2, json!({ "t": "one again" })),
+                (2, 1, json!({ "t": "two" })),
+                (5, 1, json!({ "t": "five" })),
+            ]
+        );
+        assert_eq!(tombstones, HashMap::from([(1, 60)]));
+        assert_eq!(next_id, 6);
+    }
+
+    #[test]
+    fn replay_stops_at_a_torn_record() {
+        let mut log = record(&WalRecord::insert(&doc(1, 1, "one")).unwrap());
+        let whole = log.len();
+        let torn = record(&WalRecord::insert(&doc(2, 1, "two")).unwrap());
+        log.extend_from_slice(&torn[..torn.len() - 3]);
+        let (mut docs, mut tombstones, mut next_id) = (Vec::new(), HashMap::new(), 1);
+        assert_eq!(replay_wal(&mut docs, &mut tombstones, &mut next_id, &log), (whole, 1));
+        assert_eq!(docs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn load_truncates_a_torn_log_tail() {
+        let config = config("torn", StorageFormat::Bincode, false);
+        let records = [
+            WalRecord::insert(&doc(1, 1, "one")).unwrap(),
+            WalRecord::insert(&doc(2, 1, "two")).unwrap(),
+        ];
+        append_log(&config, "books", &records).await.unwrap();
+        let wal = wal_path(&config.data_dir, "books");
+        let valid = std::fs::metadata(&wal).unwrap().len();
+        let mut content = std::fs::read(&wal).unwrap();
+        content.extend_from_slice(&[200, 0, 0, 0, 1, 2]);
+        std::fs::write(&wal, content).unwrap();
+
+        let indexes = load_indexes(&config).await;
+        assert_eq!(indexes.read().await["books"].docs.len(), 2);
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), valid);
+
+        // Appends after the truncation are read back too.
+        append_log(&config, "books", &[WalRecord::Tombstone(1, 5)]).await.unwrap();
+        let indexes = load_indexes(&config).await;
+        let map = indexes.read().await;
+        assert_eq!(map["books"].tombstones, HashMap::from([(1, 5)]));
+        assert_eq!(map["books"].wal_records, 3);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshots_round_trip_in_every_format() {
+        let docs = vec![doc(1, 3, "one"), doc(2, 1, "two"), doc(4, 2, "four")];
+        let tombstones = HashMap::from([(2, 1234)]);
+        for format in [StorageFormat::Bincode, StorageFormat::Json] {
+            for compress in [false, true] {
+                let config = config(&format!("snapshot-{}-{compress}", format.extension()), format, compress);
+                append_log(&config, "books", &[WalRecord::Undelete(2)]).await.unwrap();
+                compact_index(&config, "books", &docs, &tombstones, 9).await.unwrap();
+                assert!(!wal_path(&config.data_dir, "books").exists());
+
+                let bytes = std::fs::read(snapshot_path(&config.data_dir, "books", format)).unwrap();
+                assert_eq!(bytes.starts_with(&ZSTD_MAGIC), compress);
+                let indexes = load_indexes(&config).await;
+                let map = indexes.read().await;
+                let index = &map["books"];
+                assert_eq!(contents(&index.docs), contents(&docs));
+                assert_eq!(index.tombstones, tombstones);
+                assert_eq!(index.next_id(), 9);
+                assert_eq!(index.wal_records, 0);
+                std::fs::remove_dir_all(&config.data_dir).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_folds_set_aside_logs_into_the_snapshot() {
+        let mut config = config("flush", StorageFormat::Bincode, false);
+        config.tombstone_retention = Some(std::time::Duration::from_secs(60));
+        let old = crate::now_millis() - 120_000;
+        // A compaction that failed after setting its log aside.
+        let rotated = record(&WalRecord::insert(&doc(1, 1, "one")).unwrap());
+        std::fs::write(rotated_wal_path(&config.data_dir, "books"), rotated).unwrap();
+        let records = [WalRecord::insert(&doc(2, 1, "two")).unwrap(), WalRecord::Tombstone(1, old)];
+        append_log(&config, "books", &records).await.unwrap();
+
+        let indexes = load_indexes(&config).await;
+        assert_eq!(indexes.read().await["books"].wal_records, 3);
+        flush_indexes(&indexes, &config, 1).await;
+        assert!(!wal_path(&config.data_dir, "books").exists());
+        assert!(!rotated_wal_path(&config.data_dir, "books").exists());
+        assert_eq!(indexes.read().await["books"].wal_records, 0);
+
+        // The document deleted past the retention is purged for good.
+        let reloaded = load_indexes(&config).await;
+        let map = reloaded.read().await;
+        assert_eq!(contents(&map["books"].docs), contents(&[doc(2, 1, "two")]));
+        assert!(map["books"].tombstones.is_empty());
+        assert_eq!(map["books"].next_id(), 3);
+        std::fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn bare_document_lists_still_load() {
+        let raw = vec![
+            PersistedDocument::encode(&doc(3, 1, "three")).unwrap(),
+            PersistedDocument::encode(&doc(1, 1, "one")).unwrap(),
+        ];
+        let snapshot = decode_snapshot(&bincode::serialize(&raw).unwrap()).unwrap();
+        assert_eq!(snapshot.docs.iter().map(|d| d.id).collect::<Vec<_>>(), [1, 3]);
+        assert_eq!(snapshot.next_id, 4);
+
+        let twice = vec![
+            PersistedDocument::encode(&doc(1, 1, "one")).unwrap(),
+            PersistedDocument::encode(&doc(1, 1, "again")).unwrap(),
+        ];
+        assert!(decode_snapshot(&bincode::serialize(&twice).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+//! JSON bodies that refuse keys their route does not know, so a misspelt parameter is an
+//! error instead of being silently ignored.
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use warp::{Filter, Rejection};
+
+use crate::body::InvalidBody;
+
+/// Rejection for a body naming a field the route does not accept.
+#[derive(Debug)]
+pub struct UnknownField(pub String);
+
+impl warp::reject::Reject for UnknownField {}
+
+/// Parses a body of at most `limit` bytes as `T` after `unknown` has found no unexpected
+/// field in it. `unknown` returns the path of the first such field, e.g. `rang` or
+/// `[1].query.rang`.
+pub fn body<T>(
+    limit: u64,
+    unknown: fn(&Value) -> Option<String>,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    crate::body::json::<Value>(limit).and_then(move |body: Value| async move {
+        if let Some(field) = unknown(&body) {
+            return Err(warp::reject::custom(UnknownField(field)));
+        }
+        serde_json::from_value(body).map_err(|e| warp::reject::custom(InvalidBody(e.to_string())))
+    })
+}
+
+/// The first key of `value`, if it is an object, missing from `known`.
+pub fn unknown_key(value: &Value, known: &[&str]) -> Option<String> {
+    value
+        .as_object()?
+        .keys()
+        .find(|key| !known.contains(&key.as_str()))
+        .cloned()
+}
@@ -0,0 +1,497 @@
+use std::collections::HashSet;
+
+use serde_json::{Map, Value};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Byte ranges of the alphanumeric runs in `text`.
+fn token_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                spans.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+/// How tokens are normalized before they are indexed or compared. The inverted index
+/// always holds tokens normalized with the default settings.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Normalizer {
+    /// Keep letter case instead of lowercasing.
+    pub case_sensitive: bool,
+    /// Strip diacritics, so `café` and `cafe` compare equal.
+    pub fold: bool,
+}
+
+impl Normalizer {
+    pub fn normalize(self, token: &str) -> String {
+        let token = if self.case_sensitive {
+            token.to_string()
+        } else {
+            token.to_lowercase()
+        };
+        if self.fold { fold(&token) } else { token }
+    }
+}
+
+/// Removes the combining marks left after canonical decomposition, e.g. `é` becomes `e`.
+fn fold(text: &str) -> String {
+    text.nfd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+/// Splits `text` into alphanumeric tokens normalized by `normalizer`.
+pub fn tokenize(text: &str, normalizer: Normalizer) -> Vec<String> {
+    token_spans(text)
+        .into_iter()
+        .map(|(s, e)| normalizer.normalize(&text[s..e]))
+        .collect()
+}
+
+/// Calls `f` with every string, number and boolean inside `value`. Object keys are skipped.
+fn for_each_text(value: &Value, f: &mut impl FnMut(&str)) {
+    match value {
+        Value::String(s) => f(s),
+        Value::Number(n) => f(&n.to_string()),
+        Value::Bool(b) => f(if *b { "true" } else { "false" }),
+        Value::Array(items) => items.iter().for_each(|v| for_each_text(v, f)),
+        Value::Object(map) => map.values().for_each(|v| for_each_text(v, f)),
+        Value::Null => {}
+    }
+}
+
+/// The stopword set selected with `STOPWORDS=english`.
+const ENGLISH_STOPWORDS: [&str; 33] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// Filters applied after tokenizing and normalizing: dropping stopwords and, optionally,
+/// stemming. The same analyzer produces the indexed tokens and the query terms, so both
+/// sides always agree.
+#[derive(Default)]
+pub struct Analyzer {
+    stopwords: HashSet<String>,
+    stem: bool,
+}
+
+impl Analyzer {
+    /// `stopwords` are matched after lowercasing; `english` expands to a built-in list.
+    pub fn new<'a>(stopwords: impl IntoIterator<Item = &'a str>, stem: bool) -> Self {
+        let mut set = HashSet::new();
+        for word in stopwords {
+            match word {
+                "english" => set.extend(ENGLISH_STOPWORDS.iter().map(|w| w.to_string())),
+                word => {
+                    set.insert(word.to_lowercase());
+                }
+            }
+        }
+        Analyzer { stopwords: set, stem }
+    }
+
+    /// Turns a normalized token into the term stored or searched for; `None` for stopwords.
+    pub fn term(&self, token: String) -> Option<String> {
+        if self.stopwords.contains(&token.to_lowercase()) {
+            return None;
+        }
+        Some(if self.stem { stem(&token) } else { token })
+    }
+
+    /// Analyzed terms of `text`, in order.
+    pub fn tokens(&self, text: &str, normalizer: Normalizer) -> Vec<String> {
+        tokenize(text, normalizer)
+            .into_iter()
+            .filter_map(|token| self.term(token))
+            .collect()
+    }
+
+    /// Whether a single string, number or boolean inside `value` has tokens matching `terms`
+    /// consecutively and in order. Stopwords removed by the analyzer do not break a phrase.
+    pub fn contains_phrase(&self, value: &Value, terms: &[QueryTerm], normalizer: Normalizer) -> bool {
+        if terms.is_empty() {
+            return true;
+        }
+        let mut found = false;
+        for_each_text(value, &mut |text| {
+            if !found {
+                let tokens = self.tokens(text, normalizer);
+                found = tokens
+                    .windows(terms.len())
+                    .any(|window| window.iter().zip(terms).all(|(token, term)| term.matches(token)));
+            }
+        });
+        found
+    }
+
+    /// Analyzed terms of every string, number and boolean inside `value`.
+    pub fn value_tokens(&self, value: &Value, normalizer: Normalizer) -> Vec<String> {
+        let mut tokens = Vec::new();
+        for_each_text(value, &mut |text| tokens.extend(self.tokens(text, normalizer)));
+        tokens
+    }
+}
+
+/// A light English stemmer that strips plural and `-ing`/`-ed` endings, e.g. `studies`
+/// becomes `study` and `running` becomes `run`. A suffix is only removed when at least three
+/// characters, including a vowel, remain.
+fn stem(token: &str) -> String {
+    const RULES: [(&str, &str, bool); 5] = [
+        // (suffix, replacement, undouble a trailing consonant pair)
+        ("sses", "ss", false),
+        ("ies", "y", false),
+        ("ing", "", true),
+        ("ed", "", true),
+        ("s", "", false),
+    ];
+    for (suffix, replacement, undouble) in RULES {
+        let Some(base) = token.strip_suffix(suffix) else {
+            continue;
+        };
+        if suffix == "s" && (base.ends_with('s') || base.ends_with('u') || base.ends_with('i')) {
+            return token.to_string();
+        }
+        let mut stemmed = format!("{base}{replacement}");
+        if stemmed.chars().count() < 3 || !stemmed.chars().any(|c| "aeiouy".contains(c)) {
+            return token.to_string();
+        }
+        let mut tail = stemmed.chars().rev();
+        if undouble
+            && let (Some(last), Some(prev)) = (tail.next(), tail.next())
+            && last == prev
+            && !"aeiouylsz".contains(last)
+        {
+            stemmed.pop();
+        }
+        return stemmed;
+    }
+    token.to_string()
+}
+
+/// One term of a search query, matched against individual tokens.
+pub enum QueryTerm {
+    Exact(String),
+    Wildcard(Wildcard),
+    Typeahead(Typeahead),
+}
+
+impl QueryTerm {
+    pub fn matches(&self, token: &str) -> bool {
+        match self {
+            QueryTerm::Exact(term) => term == token,
+            QueryTerm::Wildcard(pattern) => pattern.matches(token),
+            QueryTerm::Typeahead(prefix) => prefix.matches(token),
+        }
+    }
+
+    /// How much a match on `token` counts towards the score, from 0 to 1.
+    pub fn weight(&self, token: &str) -> f64 {
+        match self {
+            QueryTerm::Typeahead(prefix) if !prefix.is_prefix_of(token) => TYPEAHEAD_FUZZY_WEIGHT,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Share of the score a fuzzy typeahead match gets compared with an exact prefix.
+const TYPEAHEAD_FUZZY_WEIGHT: f64 = 0.5;
+
+/// A partly typed word, matched against the start of tokens with a few typos allowed: `hte`
+/// matches `hotel` because it is one edit away from `hote`. Words of up to two characters
+/// must match exactly, up to five may be one edit off and longer ones two. Insertions,
+/// deletions, substitutions and swaps of adjacent characters each count as one edit.
+pub struct Typeahead {
+    prefix: Vec<char>,
+    max_edits: usize,
+}
+
+impl Typeahead {
+    pub fn new(prefix: &str) -> Self {
+        let prefix: Vec<char> = prefix.chars().collect();
+        let max_edits = match prefix.len() {
+            0..=2 => 0,
+            3..=5 => 1,
+            _ => 2,
+        };
+        Typeahead { prefix, max_edits }
+    }
+
+    pub fn is_prefix_of(&self, token: &str) -> bool {
+        let mut chars = token.chars();
+        self.prefix.iter().all(|c| chars.next() == Some(*c))
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        self.is_prefix_of(token) || (self.max_edits > 0 && self.prefix_distance(token) <= self.max_edits)
+    }
+
+    /// Fewest edits turning the typed word into some prefix of `token`.
+    fn prefix_distance(&self, token: &str) -> usize {
+        // Prefixes longer than this are more than `max_edits` away.
+        let token: Vec<char> = token.chars().take(self.prefix.len() + self.max_edits).collect();
+        // Rows of the optimal string alignment table for the typed word's first i - 2, i - 1
+        // and i characters against each prefix of `token`.
+        let mut before: Vec<usize> = Vec::new();
+        let mut previous: Vec<usize> = (0..=token.len()).collect();
+        for i in 1..=self.prefix.len() {
+            let mut row = vec![i; token.len() + 1];
+            for j in 1..=token.len() {
+                let cost = usize::from(self.prefix[i - 1] != token[j - 1]);
+                row[j] = (previous[j] + 1).min(row[j - 1] + 1).min(previous[j - 1] + cost);
+                let swapped = i > 1
+                    && j > 1
+                    && self.prefix[i - 1] == token[j - 2]
+                    && self.prefix[i - 2] == token[j - 1];
+                if swapped {
+                    row[j] = row[j].min(before[j - 2] + 1);
+                }
+            }
+            before = std::mem::replace(&mut previous, row);
+        }
+        previous.into_iter().min().unwrap_or(0)
+    }
+}
+
+enum Glob {
+    Char(char),
+    One,
+    Any,
+}
+
+/// A glob matched against a whole token: `*` matches any run of characters
+/// (including none) and `?` exactly one. Neither matches across token boundaries.
+/// A backslash makes the next character literal. Literal characters are normalized like
+/// the tokens the pattern is matched against.
+pub struct Wildcard {
+    parts: Vec<Glob>,
+}
+
+impl Wildcard {
+    pub fn parse(pattern: &str, normalizer: Normalizer) -> Self {
+        let mut parts = Vec::new();
+        let normalized = normalizer.normalize(pattern);
+        let mut chars = normalized.chars();
+        while let Some(c) = chars.next() {
+            parts.push(match c {
+                '*' => Glob::Any,
+                '?' => Glob::One,
+                '\\' => match chars.next() {
+                    Some(next) => Glob::Char(next),
+                    None => Glob::Char('\\'),
+                },
+                c => Glob::Char(c),
+            });
+        }
+        Wildcard { parts }
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        let token: Vec<char> = token.chars().collect();
+        let (mut p, mut i) = (0, 0);
+        // Last `*` seen and the token position it is currently assumed to extend to.
+        let mut star: Option<(usize, usize)> = None;
+        while i < token.len() {
+            match self.parts.get(p) {
+                Some(Glob::Any) => {
+                    star = Some((p, i));
+                    p += 1;
+                }
+                Some(Glob::One) => {
+                    p += 1;
+                    i += 1;
+                }
+                Some(Glob::Char(c)) if *c == token[i] => {
+                    p += 1;
+                    i += 1;
+                }
+                _ => match star {
+                    Some((sp, si)) => {
+                        p = sp + 1;
+                        i = si + 1;
+                        star = Some((sp, si + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.parts[p..].iter().all(|g| matches!(g, Glob::Any))
+    }
+}
+
+/// Wraps each token of `text` whose analyzed term matches one of `terms` with
+/// `<tag>`/`</tag>`. The text is HTML-escaped, so only the inserted tags are markup.
+/// Returns `None` when no token matched.
+pub fn highlight(
+    text: &str,
+    terms: &[QueryTerm],
+    analyzer: &Analyzer,
+    normalizer: Normalizer,
+    tag: &str,
+) -> Option<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (s, e) in token_spans(text) {
+        let Some(token) = analyzer.term(normalizer.normalize(&text[s..e])) else {
+            continue;
+        };
+        if terms.iter().any(|t| t.matches(&token)) {
+            escape_html(&text[last..s], &mut out);
+            out.push_str(&format!("<{tag}>"));
+            escape_html(&text[s..e], &mut out);
+            out.push_str(&format!("</{tag}>"));
+            last = e;
+        }
+    }
+    if last == 0 {
+        return None;
+    }
+    escape_html(&text[last..], &mut out);
+    Some(out)
+}
+
+/// Appends `text` to `out` with the characters that are special in HTML replaced by entities.
+fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Mean Earth radius used for great-circle distances.
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// Great-circle distance in kilometres between two points given in degrees.
+pub fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (lon2 - lon1).to_radians();
+    let a = (dphi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+/// Reads `{"lat": .., "lon": ..}` from `value`, requiring valid coordinates.
+pub fn geo_point(value: &Value) -> Option<(f64, f64)> {
+    let lat = value.get("lat")?.as_f64()?;
+    let lon = value.get("lon")?.as_f64()?;
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some((lat, lon))
+}
+
+/// Approximate heap bytes owned by `value`, counting string contents and container slots.
+pub fn value_size(value: &Value) -> usize {
+    let slot = std::mem::size_of::<Value>();
+    match value {
+        Value::String(s) => s.len(),
+        Value::Array(items) => items.iter().map(|v| slot + value_size(v)).sum(),
+        Value::Object(map) => map.iter().map(|(k, v)| k.len() + slot + value_size(v)).sum(),
+        _ => 0,
+    }
+}
+
+/// Resolves a dotted path such as `author.name` through nested objects.
+pub fn get_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |v, key| v.get(key))
+}
+
+/// Copies only the given dotted paths of `value` into a new object, keeping their nesting.
+/// Paths that do not resolve are left out.
+pub fn select_paths(value: &Value, paths: &[&str]) -> Value {
+    let mut out = Map::new();
+    for path in paths {
+        if let Some(v) = get_path(value, path) {
+            insert_path(&mut out, path, v.clone());
+        }
+    }
+    Value::Object(out)
+}
+
+fn insert_path(out: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        None => {
+            out.insert(path.to_string(), value);
+        }
+        Some((head, rest)) => {
+            let child = out
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(child) = child {
+                insert_path(child, rest, value);
+            }
+        }
+    }
+}
+
+/// Adds to `out` the dotted path, starting at `path`, of every field under `value` whose
+/// analyzed text contains `token`. Values inside arrays count toward the array's path.
+pub fn paths_containing(value: &Value, path: &str, token: &str, analyzer: &Analyzer, out: &mut Vec<String>) {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .for_each(|v| paths_containing(v, path, token, analyzer, out)),
+        Value::Object(map) => {
+            for (key, v) in map {
+                let child = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                paths_containing(v, &child, token, analyzer, out);
+            }
+        }
+        leaf => {
+            let found = analyzer
+                .value_tokens(leaf, Normalizer::default())
+                .iter()
+                .any(|t| t == token);
+            if found && !out.iter().any(|p| p == path) {
+                out.push(path.to_string());
+            }
+        }
+    }
+}
+
+/// Adds to `out` the highlighted fragments of every string under `value` containing one of
+/// `terms`, keyed by dotted field path starting at `path`. Strings inside arrays are grouped
+/// under the array's path.
+pub fn collect_highlights(
+    value: &Value,
+    path: &str,
+    terms: &[QueryTerm],
+    analyzer: &Analyzer,
+    normalizer: Normalizer,
+    tag: &str,
+    out: &mut Map<String, Value>,
+) {
+    match value {
+        Value::String(s) => {
+            if let Some(fragment) = highlight(s, terms, analyzer, normalizer, tag) {
+                let entry = out.entry(path.to_string()).or_insert_with(|| Value::Array(Vec::new()));
+                if let Value::Array(fragments) = entry {
+                    fragments.push(Value::String(fragment));
+                }
+            }
+        }
+        Value::Array(items) => items
+            .iter()
+            .for_each(|v| collect_highlights(v, path, terms, analyzer, normalizer, tag, out)),
+        Value::Object(map) => {
+            for (key, v) in map {
+                let child = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+                collect_highlights(v, &child, terms, analyzer, normalizer, tag, out);
+            }
+        }
+        _ => {}
+    }
+}